@@ -6,24 +6,10 @@ use tokio_fastcgi::*;
 
 extern crate env_logger;
 extern crate futures;
-extern crate tokio_core;
-extern crate tokio_proto;
-extern crate tokio_uds;
 
 use futures::{Future, Stream};
-use tokio_core::reactor::Core;
-use tokio_proto::BindServer;
-use tokio_uds::*;
 
-use std::fs;
 use std::io;
-use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
-
-fn umask(mask: u32) -> u32 {
-    extern "system" { fn umask(mask: u32) -> u32; }
-    unsafe { umask(mask) }
-}
 
 fn write_base64_digit(six_bits: u8, out: &mut Vec<u8>) {
     let c = match six_bits {
@@ -155,30 +141,10 @@ impl FastcgiRequestHandler for Base64ifyHandler {
 fn main() {
     env_logger::init().unwrap();
 
-    let filename = "hello.sock";
-    if let Err(e) = fs::remove_file(filename) {
-        if e.kind() != io::ErrorKind::NotFound {
-            panic!("failed to remove existing socket file {:?}: {}", filename, e);
-        }
-    }
-
-    let mut reactor = Core::new().unwrap();
-    let handle = reactor.handle();
-    let remote = reactor.remote();
-
-    umask(0);
-    let listener = UnixListener::bind(filename).expect("failed to bind socket");
-
-    let srv = listener.incoming().for_each(|socket| {
-        println!("New connection: fd {}", socket.as_raw_fd());
-
-        let service = FastcgiService::new(remote.clone(), Arc::new(Base64ifyHandler));
-
-        let proto = FastcgiProto::default();
-        proto.bind_server(&handle, socket, service);
-
-        Ok(())
-    });
-
-    reactor.run(srv).expect("failed to run the server");
+    FastcgiServer::builder(Base64ifyHandler)
+        .bind_unix("hello.sock")
+        .with_socket_mode(0o666)
+        .build()
+        .run()
+        .expect("failed to run the server");
 }
@@ -3,26 +3,12 @@ use tokio_fastcgi::*;
 
 extern crate env_logger;
 extern crate futures;
-extern crate tokio_core;
-extern crate tokio_proto;
-extern crate tokio_uds;
 
-use futures::{Future, Stream};
-use tokio_core::reactor::Core;
-use tokio_proto::BindServer;
-use tokio_uds::*;
+use futures::Future;
 
-use std::fs;
 use std::io;
-use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-fn umask(mask: u32) -> u32 {
-    extern "system" { fn umask(mask: u32) -> u32; }
-    unsafe { umask(mask) }
-}
-
 struct HelloHandler {
     request_count: AtomicUsize,
 }
@@ -59,32 +45,18 @@ impl FastcgiRequestHandler for HelloHandler {
 fn main() {
     env_logger::init().unwrap();
 
-    let filename = "hello.sock";
-    if let Err(e) = fs::remove_file(filename) {
-        if e.kind() != io::ErrorKind::NotFound {
-            panic!("failed to remove existing socket file {:?}: {}", filename, e);
-        }
-    }
-
-    let mut reactor = Core::new().unwrap();
-    let handle = reactor.handle();
-    let remote = reactor.remote();
-
-    umask(0);
-    let listener = UnixListener::bind(filename).expect("failed to bind socket");
-
-    let handler = Arc::new(HelloHandler::new());
-
-    let srv = listener.incoming().for_each(|socket| {
-        println!("New connection: fd {}", socket.as_raw_fd());
-
-        let service = FastcgiService::new(remote.clone(), handler.clone());
-
-        let proto = FastcgiProto::default();
-        proto.bind_server(&handle, socket, service);
-
-        Ok(())
-    });
-
-    reactor.run(srv).expect("failed to run the server");
+    // When started by a FastCGI process manager like spawn-fcgi, or by systemd from a socket unit,
+    // serve on the socket it made.
+    let server = FastcgiServer::builder(HelloHandler::new());
+    let server = if is_listening_socket(FCGI_LISTENSOCK_FILENO) {
+        server.bind_listensock()
+    } else if is_socket_activated() {
+        server.bind_systemd().with_sd_notify(true)
+    } else {
+        server.bind_unix("hello.sock").with_socket_mode(0o666)
+    };
+
+    server.build()
+        .run()
+        .expect("failed to run the server");
 }
@@ -0,0 +1,40 @@
+extern crate tokio_fastcgi;
+use tokio_fastcgi::*;
+
+extern crate env_logger;
+extern crate futures;
+
+use futures::Future;
+
+use std::io;
+use std::process;
+
+struct PidHandler;
+
+impl FastcgiRequestHandler for PidHandler {
+    fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let mut headers_response = request.response();
+        headers_response.set_header("Content-Type", "text/plain");
+
+        Box::new(headers_response.send_headers()
+            .and_then(|mut body_response| {
+                let body = format!("Hello from worker {}\n", process::id());
+                body_response.buffer.append(&mut body.into_bytes());
+                body_response.finish()
+            }))
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    // Four workers, each replaced after 100 requests. Send the master SIGTTIN or SIGTTOU to add or
+    // remove a worker, SIGUSR2 to log their status, and SIGTERM to shut them all down.
+    FastcgiServer::builder(PidHandler)
+        .bind_unix("hello.sock")
+        .with_socket_mode(0o666)
+        .with_max_requests(100)
+        .build()
+        .run_prefork(4)
+        .expect("failed to run the server");
+}
@@ -0,0 +1,322 @@
+//! Logging of completed requests, in Common or Combined Log Format, or as JSON.
+
+#[cfg(feature = "signals")]
+use futures::{Future, Stream};
+#[cfg(feature = "signals")]
+use tokio_signal::unix::Signal;
+
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+#[cfg(feature = "signals")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LogFormat {
+    /// `remote - user [time] "request" status bytes`, followed by the time taken in seconds and
+    /// the request ID.
+    Common,
+
+    /// Common Log Format with the referer and user agent after the byte count, followed by the
+    /// time taken in seconds and the request ID.
+    Combined,
+
+    /// One JSON object per line.
+    Json,
+}
+
+/// What's known about a request once it has completed.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    pub request_id: u16,
+    pub time: SystemTime,
+    pub remote_addr: Option<String>,
+    pub remote_user: Option<String>,
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub protocol: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// The status sent in the `Status` header, or `None` if no headers were sent, or for a local
+    /// redirect.
+    pub status: Option<u16>,
+    /// The number of bytes in the response body.
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl AccessLogEntry {
+    /// Start an entry for a request which was received just now.
+    pub fn new(request_id: u16) -> AccessLogEntry {
+        AccessLogEntry {
+            request_id,
+            time: SystemTime::now(),
+            remote_addr: None,
+            remote_user: None,
+            method: None,
+            uri: None,
+            protocol: None,
+            referer: None,
+            user_agent: None,
+            status: None,
+            bytes: 0,
+            duration: Duration::default(),
+        }
+    }
+
+    /// Fill in the details of the request from its params.
+    pub fn set_params(&mut self, params: &HashMap<String, String>) {
+        let get = |name: &str| params.get(name).filter(|value| !value.is_empty()).cloned();
+        self.remote_addr = get("REMOTE_ADDR");
+        self.remote_user = get("REMOTE_USER");
+        self.method = get("REQUEST_METHOD");
+        self.uri = get("REQUEST_URI");
+        self.protocol = get("SERVER_PROTOCOL");
+        self.referer = get("HTTP_REFERER");
+        self.user_agent = get("HTTP_USER_AGENT");
+    }
+
+    /// Format the entry as a line, without the trailing newline.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common | LogFormat::Combined => self.format_clf(format),
+            LogFormat::Json => self.format_json(),
+        }
+    }
+
+    fn format_clf(&self, format: LogFormat) -> String {
+        fn field(value: &Option<String>) -> &str {
+            value.as_ref().map(|s| s.as_str()).unwrap_or("-")
+        }
+        fn quoted(value: &Option<String>) -> String {
+            value.as_ref()
+                .map(|s| s.replace('\\', "\\\\").replace('"', "\\\""))
+                .unwrap_or_else(|| "-".to_owned())
+        }
+
+        let request = format!("{} {} {}",
+            field(&self.method), field(&self.uri), field(&self.protocol));
+        let mut line = format!("{} - {} [{}] \"{}\" {} {}",
+            field(&self.remote_addr),
+            field(&self.remote_user),
+            clf_time(self.time),
+            quoted(&Some(request)),
+            self.status.map(|s| s.to_string()).unwrap_or_else(|| "-".to_owned()),
+            if self.bytes == 0 { "-".to_owned() } else { self.bytes.to_string() });
+        if format == LogFormat::Combined {
+            write!(line, " \"{}\" \"{}\"", quoted(&self.referer), quoted(&self.user_agent))
+                .unwrap();
+        }
+        write!(line, " {:.3} {}", self.duration.as_secs_f64(), self.request_id).unwrap();
+        line
+    }
+
+    fn format_json(&self) -> String {
+        fn string(value: &Option<String>) -> String {
+            match *value {
+                Some(ref s) => json_string(s),
+                None => "null".to_owned(),
+            }
+        }
+
+        format!("{{\"time\":\"{}\",\"request_id\":{},\"remote_addr\":{},\"remote_user\":{},\
+                 \"method\":{},\"uri\":{},\"protocol\":{},\"status\":{},\"bytes\":{},\
+                 \"duration_ms\":{:.3},\"referer\":{},\"user_agent\":{}}}",
+            rfc3339_time(self.time),
+            self.request_id,
+            string(&self.remote_addr),
+            string(&self.remote_user),
+            string(&self.method),
+            string(&self.uri),
+            string(&self.protocol),
+            self.status.map(|s| s.to_string()).unwrap_or_else(|| "null".to_owned()),
+            self.bytes,
+            self.duration.as_secs_f64() * 1000.,
+            string(&self.referer),
+            string(&self.user_agent))
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Break a time down into UTC (year, month, day, hour, minute, second).
+fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second) = utc(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+enum Target {
+    Stderr,
+    File { path: PathBuf, file: File },
+    Callback(Box<dyn Fn(&AccessLogEntry) + Send + Sync>),
+}
+
+fn open_log(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Where and how to log completed requests. Give one to `FastcgiService::with_access_log`.
+pub struct AccessLog {
+    format: LogFormat,
+    target: Mutex<Target>,
+}
+
+impl AccessLog {
+    /// Log to stderr.
+    pub fn stderr(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            target: Mutex::new(Target::Stderr),
+        }
+    }
+
+    /// Log to a file, which is appended to.
+    pub fn file<P: Into<PathBuf>>(path: P, format: LogFormat) -> io::Result<AccessLog> {
+        let path = path.into();
+        let file = open_log(&path)?;
+        Ok(AccessLog {
+            format,
+            target: Mutex::new(Target::File { path, file }),
+        })
+    }
+
+    /// Pass each entry to a function instead of writing it anywhere.
+    pub fn callback<F>(callback: F) -> AccessLog
+        where F: Fn(&AccessLogEntry) + Send + Sync + 'static
+    {
+        AccessLog {
+            format: LogFormat::Combined,
+            target: Mutex::new(Target::Callback(Box::new(callback))),
+        }
+    }
+
+    /// Open the log file again, so that logging continues in a new file after the old one was
+    /// moved away, as log rotation does. This does nothing if not logging to a file.
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Target::File { ref path, ref mut file } = *self.target.lock().unwrap() {
+            info!("reopening access log {:?}", path);
+            *file = open_log(path)?;
+        }
+        Ok(())
+    }
+
+    /// Reopen the log file every time the process gets the given signal, such as `SIGHUP` or
+    /// `SIGUSR1`. The returned future has to be run on the reactor for this to happen; it never
+    /// completes. This needs the `signals` feature.
+    #[cfg(feature = "signals")]
+    pub fn reopen_on_signal(self: Arc<Self>, signal: i32)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        Box::new(Signal::new(signal)
+            .flatten_stream()
+            .for_each(move |_signal| {
+                if let Err(e) = self.reopen() {
+                    error!("failed to reopen access log: {}", e);
+                }
+                Ok(())
+            }))
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let result = match *self.target.lock().unwrap() {
+            Target::Stderr => writeln!(io::stderr(), "{}", entry.format(self.format)),
+            Target::File { ref mut file, .. } => {
+                writeln!(file, "{}", entry.format(self.format))
+            },
+            Target::Callback(ref callback) => {
+                callback(entry);
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            error!("failed to write access log entry: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(utc(at(0)), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(utc(at(946_684_799)), (1999, 12, 31, 23, 59, 59));
+        assert_eq!(utc(at(951_827_445)), (2000, 2, 29, 12, 30, 45));
+        assert_eq!(utc(at(951_868_800)), (2000, 3, 1, 0, 0, 0));
+        assert_eq!(utc(at(1_709_164_800)), (2024, 2, 29, 0, 0, 0));
+        // 2100 isn't a leap year.
+        assert_eq!(utc(at(4_107_542_399)), (2100, 2, 28, 23, 59, 59));
+        assert_eq!(utc(at(4_107_542_400)), (2100, 3, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn before_the_epoch() {
+        assert_eq!(utc(UNIX_EPOCH - Duration::from_secs(1)), (1970, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn time_formats() {
+        assert_eq!(clf_time(at(951_827_445)), "29/Feb/2000:12:30:45 +0000");
+        assert_eq!(clf_time(at(946_684_799)), "31/Dec/1999:23:59:59 +0000");
+        assert_eq!(rfc3339_time(at(951_827_445)), "2000-02-29T12:30:45Z");
+        assert_eq!(rfc3339_time(at(0)), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a \"b\" \\ c\n\t"), "\"a \\\"b\\\" \\\\ c\\n\\t\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+    }
+}
@@ -0,0 +1,192 @@
+//! Restricting which web servers may connect over TCP, as with `FCGI_WEB_SERVER_ADDRS`.
+
+use std::env;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address, or a network of them in CIDR notation, like `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// Map IPv4 addresses in IPv6 (`::ffff:a.b.c.d`) back to IPv4, so they match IPv4 networks.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(slash) => (&s[.. slash], Some(&s[slash + 1 ..])),
+            None => (s, None),
+        };
+        let addr = canonical(addr.parse::<IpAddr>()
+            .map_err(|e| format!("invalid address {:?}: {}", addr, e))?);
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>().ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", s))?,
+            None => max_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The web servers which may connect to the server over TCP. Connections from anywhere else are
+/// closed as soon as they're accepted. Unix socket connections aren't affected.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PeerAllowList {
+    networks: Vec<Cidr>,
+}
+
+impl PeerAllowList {
+    pub fn new() -> PeerAllowList {
+        PeerAllowList::default()
+    }
+
+    /// Allow an address or network.
+    pub fn allow(mut self, cidr: Cidr) -> PeerAllowList {
+        self.networks.push(cidr);
+        self
+    }
+
+    /// Parse a comma-separated list of addresses and networks, as used by
+    /// `FCGI_WEB_SERVER_ADDRS`. Spaces around the commas are ignored.
+    pub fn parse(list: &str) -> Result<PeerAllowList, String> {
+        let networks = list.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Cidr>, String>>()?;
+        Ok(PeerAllowList { networks })
+    }
+
+    /// Read the list from `FCGI_WEB_SERVER_ADDRS`, if it's set.
+    pub fn from_env() -> io::Result<Option<PeerAllowList>> {
+        match env::var("FCGI_WEB_SERVER_ADDRS") {
+            Ok(list) => PeerAllowList::parse(&list).map(Some).map_err(|e| {
+                let msg = format!("invalid FCGI_WEB_SERVER_ADDRS: {}", e);
+                error!("{}", msg);
+                io::Error::new(io::ErrorKind::InvalidInput, msg)
+            }),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => {
+                let msg = format!("invalid FCGI_WEB_SERVER_ADDRS: {}", e);
+                error!("{}", msg);
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            },
+        }
+    }
+
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(addr))
+    }
+}
+
+impl fmt::Display for PeerAllowList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let networks: Vec<String> = self.networks.iter().map(|cidr| cidr.to_string()).collect();
+        write!(f, "{}", networks.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidrs() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("127.0.0.1").to_string(), "127.0.0.1/32");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("::ffff:192.168.0.1").to_string(), "192.168.0.1/32");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn parse_invalid_cidrs() {
+        for s in &["", "10.0.0", "10.0.0.0/", "10.0.0.0/33", "::/129", "10.0.0.0/x", "host"] {
+            assert!(s.parse::<Cidr>().is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn contains_v4() {
+        let network = cidr("192.168.16.0/20");
+        assert!(network.contains(addr("192.168.16.0")));
+        assert!(network.contains(addr("192.168.31.255")));
+        assert!(!network.contains(addr("192.168.32.0")));
+        assert!(!network.contains(addr("192.168.15.255")));
+        assert!(network.contains(addr("::ffff:192.168.20.1")));
+        assert!(!network.contains(addr("::1")));
+
+        assert!(cidr("0.0.0.0/0").contains(addr("8.8.8.8")));
+        assert!(cidr("127.0.0.1").contains(addr("127.0.0.1")));
+        assert!(!cidr("127.0.0.1").contains(addr("127.0.0.2")));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let network = cidr("2001:db8::/32");
+        assert!(network.contains(addr("2001:db8::1")));
+        assert!(network.contains(addr("2001:db8:ffff::1")));
+        assert!(!network.contains(addr("2001:db9::1")));
+        assert!(!network.contains(addr("10.0.0.1")));
+
+        assert!(cidr("::/0").contains(addr("fe80::1")));
+        assert!(cidr("::1").contains(addr("::1")));
+        assert!(!cidr("::1").contains(addr("::2")));
+    }
+
+    #[test]
+    fn allow_lists() {
+        let list = PeerAllowList::parse(" 127.0.0.1, 10.0.0.0/8 ,,::1").unwrap();
+        assert_eq!(list.to_string(), "127.0.0.1/32,10.0.0.0/8,::1/128");
+        assert!(list.allows(addr("10.1.2.3")));
+        assert!(list.allows(addr("::1")));
+        assert!(!list.allows(addr("192.168.0.1")));
+        assert!(!PeerAllowList::new().allows(addr("127.0.0.1")));
+        assert!(PeerAllowList::parse("127.0.0.1,bad").is_err());
+    }
+}
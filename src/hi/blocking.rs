@@ -0,0 +1,420 @@
+//! Running synchronous request handlers, which want `Read` and `Write` rather than futures, on a
+//! pool of threads.
+
+use super::super::*;
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, sink, stream, Future, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use futures_cpupool::{Builder, CpuPool};
+use tokio_io::io::{flush, shutdown, write_all};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::str;
+use std::sync::Arc;
+
+/// The most output a handler buffers before sending it on, which is what fits in one record.
+const MAX_RECORD_LEN: usize = 0xFFFF;
+
+/// The number of chunks of output a handler can get ahead of the web server by before its writes
+/// block.
+const OUTPUT_QUEUE_LEN: usize = 4;
+
+/// The most the response headers a handler writes may add up to.
+const MAX_HEADERS_LEN: usize = 64 * 1024;
+
+/// A request handler for synchronous code, run on a thread of a `BlockingPool`.
+pub trait BlockingHandler: Send + Sync + 'static {
+    /// Handle a request, given its params, its body as `stdin`, and `stdout` to write the response
+    /// to, as a CGI script would: the headers, like `Content-Type` and `Status`, one per line,
+    /// then a blank line, then the body.
+    ///
+    /// Reads block until the web server sends more of the body, and writes block while the web
+    /// server is behind on taking the response. Flushing `stdout` sends what's been written so far.
+    /// Returning an error before the end of the headers responds with an error page, as for a
+    /// `FastcgiRequestHandler`.
+    fn handle(&self, params: &HashMap<String, String>, stdin: &mut dyn Read,
+              stdout: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Runs a `BlockingHandler` on a pool of threads, as a `FastcgiRequestHandler`.
+pub struct BlockingPool<B> {
+    handler: Arc<B>,
+    pool: CpuPool,
+}
+
+impl<B: BlockingHandler> BlockingPool<B> {
+    /// Run the handler on a pool of this many threads, or at least one. Up to that many requests
+    /// are handled at once, and the rest wait for a thread to be free.
+    pub fn new(handler: B, threads: usize) -> BlockingPool<B> {
+        BlockingPool {
+            handler: Arc::new(handler),
+            pool: Builder::new()
+                .pool_size(threads.max(1))
+                .name_prefix("fastcgi-blocking-")
+                .create(),
+        }
+    }
+}
+
+/// What a handler writes to its stdout.
+enum Output {
+    Headers(Vec<(String, String)>),
+    Body(Bytes),
+}
+
+fn invalid_output<T: Into<String>>(msg: T) -> io::Error {
+    let msg = msg.into();
+    error!("{}", msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Find the blank line after the headers, returning where the headers end and the body starts.
+/// Line breaks before `from` aren't looked at.
+fn find_headers_end(data: &[u8], from: usize) -> Option<(usize, usize)> {
+    if from == 0 {
+        if data.starts_with(b"\r\n") {
+            return Some((0, 2));
+        } else if data.starts_with(b"\n") {
+            return Some((0, 1));
+        }
+    }
+    data.iter()
+        .enumerate()
+        .skip(from)
+        .filter(|&(_, byte)| *byte == b'\n')
+        .filter_map(|(i, _)| {
+            let rest = &data[i + 1 ..];
+            if rest.starts_with(b"\r\n") {
+                Some((i + 1, i + 3))
+            } else if rest.starts_with(b"\n") {
+                Some((i + 1, i + 2))
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+fn parse_headers(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let text = str::from_utf8(data)
+        .map_err(|_| invalid_output("response headers aren't valid UTF-8"))?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let colon = line.find(':')
+                .ok_or_else(|| invalid_output(format!("invalid response header {:?}", line)))?;
+            Ok((line[.. colon].trim().to_owned(), line[colon + 1 ..].trim().to_owned()))
+        })
+        .collect()
+}
+
+/// The request body, as a blocking `Read`.
+struct Stdin<S: Stream> {
+    body: stream::Wait<S>,
+    chunk: BytesMut,
+    eof: bool,
+}
+
+impl<S: Stream<Item=BytesMut, Error=io::Error>> Read for Stdin<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() && !self.eof {
+            match self.body.next() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Err(e),
+                None => self.eof = true,
+            }
+        }
+        let len = cmp::min(buf.len(), self.chunk.len());
+        buf[.. len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// The response, as a blocking `Write`. The headers are parsed out of the output, and the body is
+/// sent on in chunks.
+struct Stdout {
+    sender: sink::Wait<mpsc::Sender<Output>>,
+    /// The headers written so far, until the blank line after them.
+    headers: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl Stdout {
+    fn send(&mut self, output: Output) -> io::Result<()> {
+        self.sender.send(output)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the response was closed"))
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = mem::take(&mut self.buffer);
+        self.send(Output::Body(Bytes::from(data)))
+    }
+
+    /// Send whatever is left once the handler is done. Headers without a blank line after them
+    /// still count as headers.
+    fn finish(mut self) -> io::Result<()> {
+        if let Some(headers) = self.headers.take() {
+            let headers = parse_headers(&headers)?;
+            self.send(Output::Headers(headers))?;
+        }
+        self.send_buffer()?;
+        self.sender.flush()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the response was closed"))
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(mut headers) = self.headers.take() {
+            let written = headers.len();
+            headers.extend_from_slice(buf);
+            // The blank line may have begun in the last two bytes already written.
+            match find_headers_end(&headers, written.saturating_sub(2)) {
+                Some((end, body_start)) => {
+                    let parsed = parse_headers(&headers[.. end])?;
+                    self.send(Output::Headers(parsed))?;
+                    // The body is written by the next call.
+                    return Ok(body_start - written);
+                },
+                None if headers.len() > MAX_HEADERS_LEN => {
+                    return Err(invalid_output("response headers are too long"));
+                },
+                None => {
+                    self.headers = Some(headers);
+                    return Ok(buf.len());
+                },
+            }
+        }
+
+        if self.buffer.len() >= MAX_RECORD_LEN {
+            self.send_buffer()?;
+        }
+        let len = cmp::min(buf.len(), MAX_RECORD_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[.. len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.headers.is_none() {
+            self.send_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: BlockingHandler> FastcgiRequestHandler for BlockingPool<B> {
+    fn call(&self, mut request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let body = mem::replace(&mut request.body, Box::new(stream::empty()));
+        let params = mem::take(&mut request.params);
+        let (sender, receiver) = mpsc::channel(OUTPUT_QUEUE_LEN);
+        let handler = self.handler.clone();
+        let handled = self.pool.spawn_fn(move || {
+            let mut stdin = Stdin { body: body.wait(), chunk: BytesMut::new(), eof: false };
+            let mut stdout = Stdout {
+                sender: sender.wait(),
+                headers: Some(vec![]),
+                buffer: vec![],
+            };
+            handler.handle(&params, &mut stdin, &mut stdout)?;
+            stdout.finish()
+        });
+
+        // Meanwhile, pass the output on to the web server. If the handler fails before finishing
+        // the headers, there is none, and its error gets an error page.
+        let mut response = request.response();
+        let output = receiver.into_future()
+            .map_err(|_| unreachable!("receivers don't fail"))
+            .and_then(move |(first, rest)| {
+                let headers = match first {
+                    Some(Output::Headers(headers)) => headers,
+                    Some(Output::Body(_)) => unreachable!("the headers are sent first"),
+                    None => return Either::A(future::ok(())),
+                };
+                // Headers can be repeated, like Set-Cookie, and each one is sent.
+                let mut names = HashSet::new();
+                for (name, value) in headers {
+                    if names.insert(name.to_ascii_lowercase()) {
+                        response.set_header(name, value);
+                    } else {
+                        response.add_header(name, value);
+                    }
+                }
+                Either::B(response.send_headers()
+                    .and_then(move |body_response| {
+                        // Each chunk is sent on as it comes, since the handler flushed it.
+                        let writer = body_response.into_writer();
+                        rest.map_err(|_| unreachable!("receivers don't fail"))
+                            .fold(writer, |writer, output| match output {
+                                Output::Body(data) => {
+                                    write_all(writer, data).and_then(|(writer, _)| flush(writer))
+                                },
+                                Output::Headers(_) => unreachable!("the headers are sent once"),
+                            })
+                    })
+                    .and_then(shutdown)
+                    .map(|_| ()))
+            });
+
+        Box::new(handled.join(output).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::response::RequestState;
+
+    use tokio_proto::streaming::Body;
+
+    #[test]
+    fn headers_end() {
+        assert_eq!(find_headers_end(b"A: b\n\nbody", 0), Some((5, 6)));
+        assert_eq!(find_headers_end(b"A: b\r\n\r\nbody", 0), Some((6, 8)));
+        assert_eq!(find_headers_end(b"A: b\nC: d\n\n", 0), Some((10, 11)));
+        assert_eq!(find_headers_end(b"\r\nbody", 0), Some((0, 2)));
+        assert_eq!(find_headers_end(b"\nbody", 0), Some((0, 1)));
+        assert_eq!(find_headers_end(b"A: b\r\n", 0), None);
+        assert_eq!(find_headers_end(b"A: b\r\nC: d\r", 0), None);
+        assert_eq!(find_headers_end(b"", 0), None);
+    }
+
+    #[test]
+    fn headers_end_from() {
+        assert_eq!(find_headers_end(b"A: b\n\n", 3), Some((5, 6)));
+        assert_eq!(find_headers_end(b"A: b\n\n", 5), None);
+        // A blank line at the start is only found from the start.
+        assert_eq!(find_headers_end(b"\r\nbody", 1), None);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_headers(b"Content-Type: text/plain\nStatus:404 Not Found\r\n").unwrap(),
+                   vec![("Content-Type".to_owned(), "text/plain".to_owned()),
+                        ("Status".to_owned(), "404 Not Found".to_owned())]);
+        assert_eq!(parse_headers(b"Location: http://example.com/a:b").unwrap(),
+                   vec![("Location".to_owned(), "http://example.com/a:b".to_owned())]);
+        assert!(parse_headers(b"").unwrap().is_empty());
+        assert!(parse_headers(b"no colon\n").is_err());
+        assert!(parse_headers(b"Bad: \xff\n").is_err());
+    }
+
+    /// Write each of the chunks to a `Stdout` in turn, returning the headers and body it sends.
+    fn output(writes: &[&[u8]]) -> (Vec<(String, String)>, Vec<u8>) {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut stdout = Stdout {
+            sender: sender.wait(),
+            headers: Some(vec![]),
+            buffer: vec![],
+        };
+        for data in writes {
+            stdout.write_all(data).unwrap();
+        }
+        stdout.finish().unwrap();
+
+        let mut headers = None;
+        let mut body = vec![];
+        for output in receiver.collect().wait().unwrap() {
+            match output {
+                Output::Headers(sent) => {
+                    assert!(headers.is_none(), "headers sent twice");
+                    headers = Some(sent);
+                },
+                Output::Body(data) => body.extend_from_slice(&data),
+            }
+        }
+        (headers.expect("no headers sent"), body)
+    }
+
+    fn content_type() -> Vec<(String, String)> {
+        vec![("Content-Type".to_owned(), "text/plain".to_owned())]
+    }
+
+    #[test]
+    fn write_all_at_once() {
+        assert_eq!(output(&[b"Content-Type: text/plain\r\n\r\nbody"]),
+                   (content_type(), b"body".to_vec()));
+        assert_eq!(output(&[b"Content-Type: text/plain\n\n"]), (content_type(), vec![]));
+    }
+
+    #[test]
+    fn blank_line_split_across_writes() {
+        assert_eq!(output(&[b"Content-Type: text/plain\n", b"\n", b"body"]),
+                   (content_type(), b"body".to_vec()));
+        assert_eq!(output(&[b"Content-Type: text/plain\r\n", b"\r", b"\nbody"]),
+                   (content_type(), b"body".to_vec()));
+        assert_eq!(output(&[b"Content-Type: text/plain\r", b"\n", b"\r", b"\n", b"body"]),
+                   (content_type(), b"body".to_vec()));
+        assert_eq!(output(&[b"Content-", b"Type: text/plain\n\n", b"bo", b"dy"]),
+                   (content_type(), b"body".to_vec()));
+    }
+
+    #[test]
+    fn body_in_same_write_as_blank_line() {
+        assert_eq!(output(&[b"Content-Type: text/plain\n", b"\nbody\n\nmore"]),
+                   (content_type(), b"body\n\nmore".to_vec()));
+    }
+
+    #[test]
+    fn headers_without_blank_line() {
+        assert_eq!(output(&[b"Content-Type: text/plain\n"]), (content_type(), vec![]));
+        assert_eq!(output(&[]), (vec![], vec![]));
+    }
+
+    #[test]
+    fn large_body() {
+        let body = vec![b'x'; MAX_RECORD_LEN * 2 + 10];
+        let (_, sent) = output(&[b"Content-Type: text/plain\n\n", &body]);
+        assert_eq!(sent, body);
+    }
+
+    #[test]
+    fn headers_too_long() {
+        let (sender, _receiver) = mpsc::channel(16);
+        let mut stdout = Stdout {
+            sender: sender.wait(),
+            headers: Some(vec![]),
+            buffer: vec![],
+        };
+        let line = format!("X-Long: {}\n", "x".repeat(100));
+        let result = (0 .. MAX_HEADERS_LEN / line.len() + 1)
+            .map(|_| stdout.write_all(line.as_bytes()))
+            .collect::<io::Result<Vec<()>>>();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    struct Cookies;
+
+    impl BlockingHandler for Cookies {
+        fn handle(&self, _params: &HashMap<String, String>, _stdin: &mut dyn Read,
+                  stdout: &mut dyn Write) -> io::Result<()> {
+            write!(stdout, "Content-Type: text/plain\nSet-Cookie: a=1\nSet-Cookie: b=2\n\nhi")
+        }
+    }
+
+    #[test]
+    fn repeated_headers() {
+        let pool = BlockingPool::new(Cookies, 0);
+        let (sender, receiver) = mpsc::channel(16);
+        let request = FastcgiRequest::new(Role::Responder, HashMap::new(), Body::empty(), 1, sender,
+                                          Arc::new(RequestState::default()));
+        pool.call(request).wait().unwrap();
+        let mut out = vec![];
+        for record in receiver.collect().wait().unwrap() {
+            if let FastcgiRecordBody::Stdout(data) = record.body {
+                out.extend_from_slice(&data);
+            }
+        }
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Set-Cookie: a=1\r\n"), "{:?}", out);
+        assert!(out.contains("Set-Cookie: b=2\r\n"), "{:?}", out);
+        assert!(out.ends_with("\r\n\r\nhi"), "{:?}", out);
+    }
+}
@@ -0,0 +1,393 @@
+//! Opt-in compression of response bodies, negotiated from the client's `Accept-Encoding` header.
+
+use super::super::*;
+
+use brotli::CompressorWriter;
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use futures::{future, Future};
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// The name of the encoding, as used in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// The encodings to use, most preferred first. The client's preferences take priority; this
+    /// order only breaks ties.
+    pub encodings: Vec<ContentEncoding>,
+
+    /// Bodies smaller than this are sent uncompressed. Because the headers have to go out before
+    /// the body, this is checked against how much of the body has been written by the first
+    /// `flush` (or `finish`).
+    pub min_size: usize,
+
+    /// Compression level, from 0 (fastest) to 9 (smallest).
+    pub level: u32,
+
+    /// Content types which are already compressed, and so are sent as they are. An entry ending in
+    /// a `/` matches every type under it.
+    pub skip_content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        let skip_content_types = [
+            "image/gif", "image/jpeg", "image/png", "image/webp", "audio/", "video/",
+            "font/woff", "font/woff2", "application/gzip", "application/x-gzip",
+            "application/x-bzip2", "application/x-xz", "application/zip",
+            "application/x-7z-compressed", "application/x-rar-compressed",
+        ];
+        CompressionConfig {
+            encodings: vec![
+                ContentEncoding::Brotli,
+                ContentEncoding::Gzip,
+                ContentEncoding::Deflate,
+            ],
+            min_size: 1024,
+            level: 6,
+            skip_content_types: skip_content_types.iter().map(|s| (*s).to_owned()).collect(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    fn skips(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.skip_content_types.iter().any(|skip| {
+            if skip.ends_with('/') {
+                mime.starts_with(skip.as_str())
+            } else {
+                mime == *skip
+            }
+        })
+    }
+}
+
+/// Pick the encoding to use, given the value of an `Accept-Encoding` header: the one with the
+/// highest quality value, with ties going to whichever comes first in `offered`.
+pub fn negotiate(accept_encoding: &str, offered: &[ContentEncoding]) -> Option<ContentEncoding> {
+    let mut qualities = HashMap::new();
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                match (kv.next().map(str::trim), kv.next()) {
+                    (Some(key), Some(value)) if key.eq_ignore_ascii_case("q") => {
+                        value.trim().parse().ok()
+                    },
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(1f32);
+        if name == "*" {
+            wildcard = Some(q);
+        } else if !name.is_empty() {
+            qualities.insert(name, q);
+        }
+    }
+
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for &encoding in offered {
+        let q = match qualities.get(encoding.name()).cloned().or(wildcard) {
+            Some(q) => q,
+            None => continue,
+        };
+        if q > 0. && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: ContentEncoding, level: u32) -> Encoder {
+        let level = level.min(9);
+        match encoding {
+            // Brotli's quality goes up to 11; its default window size is 2^22.
+            ContentEncoding::Brotli => Encoder::Brotli(
+                Box::new(CompressorWriter::new(Vec::new(), 4096, level + 2, 22))),
+            ContentEncoding::Gzip => Encoder::Gzip(
+                GzEncoder::new(Vec::new(), Compression::new(level))),
+            ContentEncoding::Deflate => Encoder::Deflate(
+                ZlibEncoder::new(Vec::new(), Compression::new(level))),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            Encoder::Brotli(ref mut w) => &mut **w,
+            Encoder::Gzip(ref mut w) => w,
+            Encoder::Deflate(ref mut w) => w,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match *self {
+            Encoder::Brotli(ref mut w) => w.get_mut(),
+            Encoder::Gzip(ref mut w) => w.get_mut(),
+            Encoder::Deflate(ref mut w) => w.get_mut(),
+        }
+    }
+
+    /// Compress the data, and return everything compressed so far, so that the client can
+    /// decompress all the data given up to now.
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.writer().write_all(data)?;
+        self.writer().flush()?;
+        Ok(mem::take(self.output()))
+    }
+
+    /// Compress the data and end the compressed stream, returning the rest of it.
+    fn finish(mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.writer().write_all(data)?;
+        match self {
+            Encoder::Brotli(w) => Ok(w.into_inner()),
+            Encoder::Gzip(w) => w.finish(),
+            Encoder::Deflate(w) => w.finish(),
+        }
+    }
+}
+
+type BodyFuture = Box<dyn Future<Item=(FastcgiBodyResponse, Option<Encoder>, Vec<u8>),
+                                 Error=io::Error>>;
+
+enum State {
+    Headers(FastcgiHeadersResponse, Option<ContentEncoding>, CompressionConfig),
+    Body(FastcgiBodyResponse, Option<Encoder>),
+}
+
+/// A response body which is compressed if the client supports it. This wraps the headers of the
+/// response, since compressing affects them, and is then used like a `FastcgiBodyResponse`: write
+/// to `buffer` and call `flush` to compress and send what's in it so far, or `finish` to end it.
+///
+/// Whether to compress is decided at the first `flush` or `finish`, when the headers are sent.
+/// Responses whose `Content-Type` is in the config's skip list, which already have a
+/// `Content-Encoding`, or which have less than the config's `min_size` bytes written by then are
+/// sent uncompressed.
+pub struct CompressedResponse {
+    state: State,
+    pub buffer: Vec<u8>,
+}
+
+impl CompressedResponse {
+    /// Wrap the headers of a response. The encoding is chosen from the request's
+    /// `HTTP_ACCEPT_ENCODING` param.
+    pub fn new(
+        headers: FastcgiHeadersResponse,
+        params: &HashMap<String, String>,
+        config: &CompressionConfig,
+        ) -> CompressedResponse
+    {
+        let encoding = params.get("HTTP_ACCEPT_ENCODING")
+            .and_then(|accept| negotiate(accept, &config.encodings));
+        debug!("negotiated compression: {:?}", encoding);
+        CompressedResponse {
+            state: State::Headers(headers, encoding, config.clone()),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Get the headers of the response, if they haven't been sent yet.
+    pub fn headers_mut(&mut self) -> Option<&mut FastcgiHeadersResponse> {
+        match self.state {
+            State::Headers(ref mut headers, _, _) => Some(headers),
+            State::Body(..) => None,
+        }
+    }
+
+    /// Decide whether to compress, and send the headers accordingly.
+    fn send_headers(
+        mut headers: FastcgiHeadersResponse,
+        encoding: Option<ContentEncoding>,
+        config: &CompressionConfig,
+        len: usize,
+        ) -> Box<dyn Future<Item=(FastcgiBodyResponse, Option<Encoder>), Error=io::Error>>
+    {
+        let skip = headers.get_header("Content-Type").map(|t| config.skips(t)).unwrap_or(false)
+            || headers.get_header("Content-Encoding").is_some();
+
+        if !skip {
+            // Whether the response is compressed depends on Accept-Encoding, so caches need to
+            // know about it, even if this particular response isn't compressed.
+            let vary = match headers.get_header("Vary") {
+                Some(vary) if vary.split(',')
+                        .any(|v| v.trim().eq_ignore_ascii_case("Accept-Encoding")) => {
+                    vary.to_owned()
+                },
+                Some(vary) => format!("{}, Accept-Encoding", vary),
+                None => "Accept-Encoding".to_owned(),
+            };
+            headers.headers_mut().retain(|name, _| !name.eq_ignore_ascii_case("Vary"));
+            headers.set_header("Vary", vary);
+        }
+
+        let encoder = match encoding {
+            Some(encoding) if !skip && len >= config.min_size => {
+                debug!("compressing response with {}", encoding.name());
+                headers.headers_mut()
+                    .retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+                headers.set_header("Content-Encoding", encoding.name());
+                Some(Encoder::new(encoding, config.level))
+            },
+            _ => None,
+        };
+
+        Box::new(headers.send_headers().map(move |body| (body, encoder)))
+    }
+
+    fn into_body(self) -> BodyFuture {
+        let buffer = self.buffer;
+        match self.state {
+            State::Headers(headers, encoding, config) => {
+                Box::new(CompressedResponse::send_headers(headers, encoding, &config, buffer.len())
+                    .map(move |(body, encoder)| (body, encoder, buffer)))
+            },
+            State::Body(body, encoder) => Box::new(future::ok((body, encoder, buffer))),
+        }
+    }
+
+    /// Compress everything in the buffer so far, and send it if the write policy says it's time
+    /// to.
+    pub fn flush(self) -> Box<dyn Future<Item=CompressedResponse, Error=io::Error>> {
+        self.flush_body(false)
+    }
+
+    /// Compress and send everything in the buffer so far, regardless of the write policy.
+    pub fn flush_now(self) -> Box<dyn Future<Item=CompressedResponse, Error=io::Error>> {
+        self.flush_body(true)
+    }
+
+    fn flush_body(self, now: bool) -> Box<dyn Future<Item=CompressedResponse, Error=io::Error>> {
+        Box::new(self.into_body()
+            .and_then(move |(mut body, mut encoder, buffer)| {
+                match encoder {
+                    Some(ref mut encoder) => body.buffer.extend(encoder.compress(&buffer)?),
+                    None => body.buffer.extend(buffer),
+                }
+                let flushed = if now { body.flush_now() } else { body.flush() };
+                Ok(flushed.map(move |body| CompressedResponse {
+                    state: State::Body(body, encoder),
+                    buffer: Vec::new(),
+                }))
+            })
+            .flatten())
+    }
+
+    /// Compress and send everything in the buffer, and end the response.
+    pub fn finish(self) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        Box::new(self.into_body()
+            .and_then(|(mut body, encoder, buffer)| {
+                match encoder {
+                    Some(encoder) => body.buffer.extend(encoder.finish(&buffer)?),
+                    None => body.buffer.extend(buffer),
+                }
+                Ok(body.finish())
+            })
+            .flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::ContentEncoding::*;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use std::io::Read;
+
+    const ALL: [ContentEncoding; 3] = [Brotli, Gzip, Deflate];
+
+    #[test]
+    fn negotiate_prefers_offered_order_on_ties() {
+        assert_eq!(negotiate("gzip, deflate, br", &ALL), Some(Brotli));
+        assert_eq!(negotiate("gzip, deflate", &ALL), Some(Gzip));
+        assert_eq!(negotiate("deflate", &ALL), Some(Deflate));
+        assert_eq!(negotiate("gzip, br", &[Gzip, Brotli]), Some(Gzip));
+    }
+
+    #[test]
+    fn negotiate_uses_quality_values() {
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.8", &ALL), Some(Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip", &ALL), Some(Gzip));
+        assert_eq!(negotiate("br ; Q=0.9 , gzip;q=0.1", &ALL), Some(Brotli));
+        assert_eq!(negotiate("br;q=0, gzip;q=0", &ALL), None);
+        assert_eq!(negotiate("br;q=nonsense", &ALL), Some(Brotli));
+    }
+
+    #[test]
+    fn negotiate_wildcard() {
+        assert_eq!(negotiate("*", &ALL), Some(Brotli));
+        assert_eq!(negotiate("br;q=0, *", &ALL), Some(Gzip));
+        assert_eq!(negotiate("*;q=0", &ALL), None);
+        assert_eq!(negotiate("*;q=0, deflate", &ALL), Some(Deflate));
+    }
+
+    #[test]
+    fn negotiate_nothing_acceptable() {
+        assert_eq!(negotiate("", &ALL), None);
+        assert_eq!(negotiate("identity", &ALL), None);
+        assert_eq!(negotiate("zstd, compress", &ALL), None);
+        assert_eq!(negotiate("gzip", &[]), None);
+        assert_eq!(negotiate("GZIP", &ALL), Some(Gzip));
+    }
+
+    #[test]
+    fn skips_content_types() {
+        let config = CompressionConfig::default();
+        assert!(config.skips("image/png"));
+        assert!(config.skips("Video/MP4; codecs=avc1"));
+        assert!(!config.skips("text/html; charset=utf-8"));
+        assert!(!config.skips("application/json"));
+    }
+
+    fn round_trip(encoding: ContentEncoding) -> Vec<u8> {
+        let mut encoder = Encoder::new(encoding, 6);
+        let mut compressed = encoder.compress(b"hello, ").unwrap();
+        compressed.extend(encoder.finish(b"world").unwrap());
+
+        let mut out = vec![];
+        match encoding {
+            Brotli => brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut out),
+            Gzip => GzDecoder::new(&compressed[..]).read_to_end(&mut out),
+            Deflate => ZlibDecoder::new(&compressed[..]).read_to_end(&mut out),
+        }.unwrap();
+        out
+    }
+
+    #[test]
+    fn encoders_round_trip() {
+        for &encoding in &ALL {
+            assert_eq!(round_trip(encoding), b"hello, world", "{:?}", encoding);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use super::super::*;
+use super::status::status_line;
+
+use futures::Future;
+
+use std::io;
+
+/// The document sent as the body of error responses which are made on a handler's behalf, such as
+/// when it fails before sending any headers.
+///
+/// Every `{status}` in the template is replaced with the HTTP status code and reason phrase.
+#[derive(Debug, Clone)]
+pub struct ErrorPage {
+    pub content_type: String,
+    pub template: String,
+}
+
+impl Default for ErrorPage {
+    fn default() -> ErrorPage {
+        ErrorPage {
+            content_type: "text/html".to_owned(),
+            template: "<!DOCTYPE html>\n<html><head><title>{status}</title></head>\n\
+                       <body><h1>{status}</h1></body></html>\n".to_owned(),
+        }
+    }
+}
+
+impl ErrorPage {
+    pub fn render(&self, status: u16) -> String {
+        self.template.replace("{status}", &status_line(status))
+    }
+
+    /// Send the page as the complete response, with the given status. Other headers, like
+    /// `Allow`, can be set on the response beforehand.
+    pub fn send(&self, mut response: FastcgiHeadersResponse, status: u16)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        response.set_status(status);
+        response.set_header("Content-Type", self.content_type.clone());
+        let page = self.render(status);
+        Box::new(response.send_headers().and_then(move |mut body| {
+            body.buffer.extend_from_slice(page.as_bytes());
+            body.finish()
+        }))
+    }
+}
@@ -2,6 +2,17 @@ use super::super::*;
 use futures::Future;
 use std::io;
 
-pub trait FastcgiRequestHandler {
+/// Handles requests. Handlers are shared between the threads of a server with `with_threads`, so
+/// they must be `Send + Sync`; the futures they return run on the thread which took the request,
+/// or with `with_handler_pool`, on a thread of the `HandlerPool`.
+pub trait FastcgiRequestHandler: Send + Sync {
     fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>>;
 }
+
+impl<F> FastcgiRequestHandler for F
+    where F: Fn(FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> + Send + Sync
+{
+    fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        self(request)
+    }
+}
@@ -0,0 +1,191 @@
+//! Running request handlers on a pool of threads, apart from the reactors which serve the
+//! connections.
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::{Core, Handle};
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+
+/// Work for a pool thread: starting a future on its reactor.
+type Job = Box<dyn FnOnce(&Handle) + Send>;
+
+/// A pool of threads, each with its own reactor, to run request handlers on. Give it to the server
+/// with `with_handler_pool`, and a handler which keeps its thread busy only holds up the other
+/// handlers on that pool thread, rather than every connection on a reactor.
+///
+/// Handler futures needn't be `Send`: the request is sent to a pool thread, and the handler is
+/// called there and its future run there. The response goes back to the connection through the
+/// request's response channel, which works from any thread.
+pub struct HandlerPool {
+    threads: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl HandlerPool {
+    /// Start a pool of this many threads. They stop once the pool is dropped, dropping any
+    /// handlers still running on them.
+    pub fn new(threads: usize) -> io::Result<HandlerPool> {
+        let mut senders = vec![];
+        for i in 0 .. threads.max(1) {
+            let (sender, receiver) = mpsc::unbounded::<Job>();
+            let (started, start) = std_mpsc::channel();
+            thread::Builder::new()
+                .name(format!("fastcgi-handler-{}", i))
+                .spawn(move || {
+                    let mut core = match Core::new() {
+                        Ok(core) => core,
+                        Err(e) => {
+                            let _ = started.send(Err(e));
+                            return;
+                        },
+                    };
+                    let _ = started.send(Ok(()));
+                    let handle = core.handle();
+                    let _ = core.run(receiver.for_each(move |job| {
+                        job(&handle);
+                        Ok(())
+                    }));
+                    debug!("handler pool thread {} stopping", i);
+                })?;
+            start.recv().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "a handler pool thread panicked"))
+            })?;
+            senders.push(sender);
+        }
+        Ok(HandlerPool {
+            threads: senders,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Make a future with the function on one of the pool's threads, and run it there. Its result
+    /// is passed back through the returned future, and if that's dropped first, so is the future
+    /// on the pool thread.
+    pub fn spawn_fn<F, R>(&self, f: F) -> Box<dyn Future<Item=(), Error=io::Error>>
+        where F: FnOnce() -> R + Send + 'static,
+              R: Future<Item=(), Error=io::Error> + 'static
+    {
+        let (done, result) = oneshot::channel();
+        let job: Job = Box::new(move |handle: &Handle| {
+            handle.spawn(Running { future: f(), done: Some(done) });
+        });
+        let i = self.next.fetch_add(1, Ordering::SeqCst) % self.threads.len();
+        if self.threads[i].unbounded_send(job).is_err() {
+            let msg = format!("handler pool thread {} has stopped", i);
+            error!("{}", msg);
+            return Box::new(future::err(io::Error::new(io::ErrorKind::Other, msg)));
+        }
+        Box::new(result.then(|result| {
+            result.unwrap_or_else(|oneshot::Canceled| {
+                Err(io::Error::new(io::ErrorKind::Other, "a handler pool thread dropped a future"))
+            })
+        }))
+    }
+}
+
+/// A future on a pool thread, which sends its result back, or stops early once that isn't wanted.
+struct Running<F> {
+    future: F,
+    done: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+impl<F: Future<Item=(), Error=io::Error>> Future for Running<F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let result = match self.future.poll() {
+            Ok(Async::Ready(())) => Ok(()),
+            Err(e) => Err(e),
+            Ok(Async::NotReady) => {
+                let done = self.done.as_mut().expect("polled after completion");
+                if let Ok(Async::Ready(())) = done.poll_cancel() {
+                    debug!("dropping a handler which is no longer waited for");
+                    return Ok(Async::Ready(()));
+                }
+                return Ok(Async::NotReady);
+            },
+        };
+        if let Some(done) = self.done.take() {
+            let _ = done.send(result);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Sends a message when dropped.
+    struct Dropped(std_mpsc::Sender<()>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let pool = HandlerPool::new(3).unwrap();
+        let (sender, names) = std_mpsc::channel();
+        let spawned = (0 .. 6)
+            .map(|_| {
+                let sender = sender.clone();
+                pool.spawn_fn(move || {
+                    let name = thread::current().name().map(str::to_owned);
+                    sender.send(name).unwrap();
+                    future::ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        future::join_all(spawned).wait().unwrap();
+        drop(sender);
+        let names = names.iter().map(Option::unwrap).collect::<HashSet<_>>();
+        let expected = (0 .. 3).map(|i| format!("fastcgi-handler-{}", i)).collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn results() {
+        let pool = HandlerPool::new(1).unwrap();
+        // The future needn't be `Send`, only the function making it.
+        pool.spawn_fn(|| {
+            let local = Rc::new(());
+            future::lazy(move || {
+                drop(local);
+                Ok(())
+            })
+        }).wait().unwrap();
+        let e = pool.spawn_fn(|| future::err(io::Error::new(io::ErrorKind::InvalidData, "bad")))
+            .wait()
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "bad");
+    }
+
+    #[test]
+    fn dropped() {
+        let pool = HandlerPool::new(1).unwrap();
+        let (sender, dropped) = std_mpsc::channel();
+        let (started, start) = std_mpsc::channel();
+        let result = pool.spawn_fn(move || {
+            let guard = Dropped(sender);
+            started.send(()).unwrap();
+            future::empty::<(), io::Error>().map(move |()| drop(guard))
+        });
+        start.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(dropped.try_recv().is_err());
+        drop(result);
+        dropped.recv_timeout(Duration::from_secs(5)).expect("the future wasn't dropped");
+    }
+}
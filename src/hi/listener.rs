@@ -0,0 +1,94 @@
+//! Listening sockets inherited from the process which started this one, like the one a FastCGI
+//! process manager leaves on `FCGI_LISTENSOCK_FILENO`.
+
+use libc;
+
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+/// A listening socket of either kind.
+#[derive(Debug)]
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// The address family of a socket.
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(libc::c_int::from(addr.ss_family))
+}
+
+/// Whether a socket is accepting connections.
+fn is_listening(fd: RawFd) -> io::Result<bool> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN,
+                         &mut value as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value != 0)
+}
+
+/// Whether the file descriptor is a listening Unix or TCP socket. Use this on
+/// `FCGI_LISTENSOCK_FILENO` to tell whether the process was started by a FastCGI process manager,
+/// or from a shell, where it would be the terminal instead.
+pub fn is_listening_socket(fd: RawFd) -> bool {
+    match socket_family(fd) {
+        Ok(libc::AF_UNIX) | Ok(libc::AF_INET) | Ok(libc::AF_INET6) => {
+            is_listening(fd).unwrap_or(false)
+        },
+        _ => false,
+    }
+}
+
+impl Listener {
+    /// Take ownership of an inherited listening socket, working out whether it's Unix or TCP. The
+    /// file descriptor is closed when the listener is dropped.
+    pub fn from_fd(fd: RawFd) -> io::Result<Listener> {
+        let family = socket_family(fd)?;
+        if !is_listening(fd)? {
+            let msg = format!("file descriptor {} is not a listening socket", fd);
+            error!("{}", msg);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let listener = match family {
+            libc::AF_UNIX => Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+            libc::AF_INET | libc::AF_INET6 => {
+                Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })
+            },
+            _ => {
+                let msg = format!("file descriptor {} is a socket of unsupported family {}",
+                                  fd, family);
+                error!("{}", msg);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        match listener {
+            Listener::Unix(ref listener) => listener.set_nonblocking(true)?,
+            Listener::Tcp(ref listener) => listener.set_nonblocking(true)?,
+        }
+        Ok(listener)
+    }
+
+    /// Make another listener for the same socket.
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match *self {
+            Listener::Unix(ref listener) => listener.try_clone().map(Listener::Unix),
+            Listener::Tcp(ref listener) => listener.try_clone().map(Listener::Tcp),
+        }
+    }
+}
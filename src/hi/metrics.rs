@@ -0,0 +1,373 @@
+//! Counters and histograms about what the server is doing, which can be rendered in the Prometheus
+//! text exposition format.
+
+use super::super::*;
+use super::router::request_path;
+
+use futures::Future;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the handler latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] =
+    [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 10.];
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics for a FastCGI server. Share one between the `FastcgiProto` and the `FastcgiService`s
+/// with `with_metrics`, and render them with a `MetricsEndpoint`.
+#[derive(Debug)]
+pub struct Metrics {
+    connections_opened: AtomicU64,
+    connections_closed: AtomicU64,
+    requests: Mutex<BTreeMap<(String, String), u64>>,
+    in_flight: AtomicU64,
+    params_bytes: AtomicU64,
+    stdin_bytes: AtomicU64,
+    stdout_bytes: AtomicU64,
+    stderr_bytes: AtomicU64,
+    handler_latency: Histogram,
+    protocol_errors: Mutex<BTreeMap<String, u64>>,
+    aborts: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+/// Counts a request as in flight until it's dropped.
+pub struct InFlight(Arc<Metrics>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            connections_opened: AtomicU64::new(0),
+            connections_closed: AtomicU64::new(0),
+            requests: Mutex::new(BTreeMap::new()),
+            in_flight: AtomicU64::new(0),
+            params_bytes: AtomicU64::new(0),
+            stdin_bytes: AtomicU64::new(0),
+            stdout_bytes: AtomicU64::new(0),
+            stderr_bytes: AtomicU64::new(0),
+            handler_latency: Histogram::new(),
+            protocol_errors: Mutex::new(BTreeMap::new()),
+            aborts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_started(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    /// Count a completed request. `status` is the HTTP status sent, if any.
+    pub fn request_finished(&self, role: Role, status: Option<u16>) {
+        let role = format!("{:?}", role).to_ascii_lowercase();
+        let status = status.map(|s| s.to_string()).unwrap_or_else(|| "none".to_owned());
+        *self.requests.lock().unwrap().entry((role, status)).or_insert(0) += 1;
+    }
+
+    pub fn handler_finished(&self, duration: Duration) {
+        self.handler_latency.observe(duration);
+    }
+
+    /// Count the bytes in a record received from the web server.
+    pub fn record_in(&self, record: &FastcgiRecord) {
+        match record.body {
+            FastcgiRecordBody::Params(ref params) => {
+                let len = params.iter().map(|(name, value)| name.len() + value.len());
+                self.params_bytes.fetch_add(len.sum::<usize>() as u64, Ordering::Relaxed);
+            },
+            FastcgiRecordBody::Stdin(ref buf) => {
+                self.stdin_bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            },
+            FastcgiRecordBody::AbortRequest => {
+                self.aborts.fetch_add(1, Ordering::Relaxed);
+            },
+            FastcgiRecordBody::UnknownType(..) => self.protocol_error("unknown_record_type"),
+            _ => (),
+        }
+    }
+
+    /// Count the bytes in a record sent to the web server.
+    pub fn record_out(&self, record: &FastcgiRecord) {
+        match record.body {
+            FastcgiRecordBody::Stdout(ref buf) => {
+                self.stdout_bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            },
+            FastcgiRecordBody::Stderr(ref buf) => {
+                self.stderr_bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            },
+            _ => (),
+        }
+    }
+
+    pub fn protocol_error(&self, kind: &str) {
+        *self.protocol_errors.lock().unwrap().entry(kind.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value)
+                .unwrap();
+        };
+        counter("fastcgi_connections_opened_total", "Connections accepted.",
+                self.connections_opened.load(Ordering::Relaxed));
+        counter("fastcgi_connections_closed_total", "Connections closed.",
+                self.connections_closed.load(Ordering::Relaxed));
+        counter("fastcgi_params_bytes_total", "Bytes of params received.",
+                self.params_bytes.load(Ordering::Relaxed));
+        counter("fastcgi_stdin_bytes_total", "Bytes of request bodies received.",
+                self.stdin_bytes.load(Ordering::Relaxed));
+        counter("fastcgi_stdout_bytes_total", "Bytes of responses sent.",
+                self.stdout_bytes.load(Ordering::Relaxed));
+        counter("fastcgi_stderr_bytes_total", "Bytes of error output sent.",
+                self.stderr_bytes.load(Ordering::Relaxed));
+        counter("fastcgi_aborts_total", "Requests aborted by the web server.",
+                self.aborts.load(Ordering::Relaxed));
+
+        writeln!(out, "# HELP fastcgi_requests_in_flight Requests being handled.\n\
+                       # TYPE fastcgi_requests_in_flight gauge\n\
+                       fastcgi_requests_in_flight {}",
+                 self.in_flight.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP fastcgi_requests_total Requests completed, by role and HTTP status.\n\
+                       # TYPE fastcgi_requests_total counter").unwrap();
+        for ((role, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "fastcgi_requests_total{{role=\"{}\",status=\"{}\"}} {}",
+                     role, status, count).unwrap();
+        }
+
+        writeln!(out, "# HELP fastcgi_protocol_errors_total Protocol errors, by kind.\n\
+                       # TYPE fastcgi_protocol_errors_total counter").unwrap();
+        for (kind, count) in self.protocol_errors.lock().unwrap().iter() {
+            writeln!(out, "fastcgi_protocol_errors_total{{kind=\"{}\"}} {}", kind, count)
+                .unwrap();
+        }
+
+        let name = "fastcgi_handler_duration_seconds";
+        writeln!(out, "# HELP {} Time taken by request handlers.\n# TYPE {} histogram",
+                 name, name).unwrap();
+        let histogram = &self.handler_latency;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}",
+                     name, bound, bucket.load(Ordering::Relaxed)).unwrap();
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(out, "{}_sum {}", name,
+                 histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6).unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+        out
+    }
+}
+
+/// Serves the metrics in the Prometheus text format. As a request handler, it responds to every
+/// request with them, for use with a `Router`. As middleware, it responds to `GET` requests for
+/// its path, and passes everything else on.
+pub struct MetricsEndpoint {
+    metrics: Arc<Metrics>,
+    path: String,
+}
+
+impl MetricsEndpoint {
+    pub fn new<S: Into<String>>(metrics: Arc<Metrics>, path: S) -> MetricsEndpoint {
+        MetricsEndpoint {
+            metrics,
+            path: path.into(),
+        }
+    }
+}
+
+impl FastcgiRequestHandler for MetricsEndpoint {
+    fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let mut response = request.response();
+        response.set_header("Content-Type", "text/plain; version=0.0.4");
+        let text = self.metrics.render();
+        Box::new(response.send_headers().and_then(move |mut body| {
+            body.buffer.extend_from_slice(text.as_bytes());
+            body.finish()
+        }))
+    }
+}
+
+impl Middleware for MetricsEndpoint {
+    fn call(&self, request: FastcgiRequest, next: &Arc<dyn FastcgiRequestHandler>)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        let is_get = request.params.get("REQUEST_METHOD")
+            .map(|method| method == "GET" || method == "HEAD")
+            .unwrap_or(false);
+        if is_get && request_path(&request.params) == self.path {
+            FastcgiRequestHandler::call(self, request)
+        } else {
+            next.call(request)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::response::RequestState;
+
+    use bytes::BytesMut;
+    use futures::{future, Stream};
+    use futures::sync::mpsc;
+    use tokio_proto::streaming::Body;
+
+    use std::collections::HashMap;
+
+    fn record(body: FastcgiRecordBody) -> FastcgiRecord {
+        FastcgiRecord { request_id: 1, body }
+    }
+
+    /// Call the handler with a request, returning the stdout of the response.
+    fn call<H: FastcgiRequestHandler>(handler: &H, method: &str, path: &str) -> String {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut params = HashMap::new();
+        params.insert("REQUEST_METHOD".to_owned(), method.to_owned());
+        params.insert("REQUEST_URI".to_owned(), path.to_owned());
+        let request = FastcgiRequest::new(Role::Responder, params, Body::empty(), 1, sender,
+                                          Arc::new(RequestState::default()));
+        future::lazy(|| handler.call(request)).wait().unwrap();
+        let mut out = vec![];
+        for record in receiver.collect().wait().unwrap() {
+            if let FastcgiRecordBody::Stdout(data) = record.body {
+                out.extend_from_slice(&data);
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn render() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        let in_flight = metrics.request_started();
+        drop(metrics.request_started());
+        metrics.request_finished(Role::Responder, Some(200));
+        metrics.request_finished(Role::Responder, Some(200));
+        metrics.request_finished(Role::Authorizer, None);
+        metrics.handler_finished(Duration::from_millis(3));
+        metrics.handler_finished(Duration::from_millis(300));
+        let params = vec![(BytesMut::from(&b"NAME"[..]), BytesMut::from(&b"value"[..]))];
+        metrics.record_in(&record(FastcgiRecordBody::Params(params)));
+        metrics.record_in(&record(FastcgiRecordBody::Stdin(BytesMut::from(&b"body"[..]))));
+        metrics.record_in(&record(FastcgiRecordBody::AbortRequest));
+        metrics.record_out(&record(FastcgiRecordBody::Stdout(BytesMut::from(&b"output"[..]))));
+        metrics.record_out(&record(FastcgiRecordBody::Stderr(BytesMut::from(&b"err"[..]))));
+        metrics.protocol_error("invalid_data");
+
+        let text = metrics.render();
+        let lines = text.lines().collect::<Vec<_>>();
+        for line in &[
+            "# HELP fastcgi_connections_opened_total Connections accepted.",
+            "# TYPE fastcgi_connections_opened_total counter",
+            "fastcgi_connections_opened_total 2",
+            "fastcgi_connections_closed_total 1",
+            "fastcgi_params_bytes_total 9",
+            "fastcgi_stdin_bytes_total 4",
+            "fastcgi_stdout_bytes_total 6",
+            "fastcgi_stderr_bytes_total 3",
+            "fastcgi_aborts_total 1",
+            "# TYPE fastcgi_requests_in_flight gauge",
+            "fastcgi_requests_in_flight 1",
+            "fastcgi_requests_total{role=\"authorizer\",status=\"none\"} 1",
+            "fastcgi_requests_total{role=\"responder\",status=\"200\"} 2",
+            "fastcgi_protocol_errors_total{kind=\"invalid_data\"} 1",
+            "# TYPE fastcgi_handler_duration_seconds histogram",
+            "fastcgi_handler_duration_seconds_bucket{le=\"0.001\"} 0",
+            "fastcgi_handler_duration_seconds_bucket{le=\"0.005\"} 1",
+            "fastcgi_handler_duration_seconds_bucket{le=\"0.25\"} 1",
+            "fastcgi_handler_duration_seconds_bucket{le=\"0.5\"} 2",
+            "fastcgi_handler_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "fastcgi_handler_duration_seconds_sum 0.303",
+            "fastcgi_handler_duration_seconds_count 2",
+        ] {
+            assert!(lines.contains(line), "no {:?} in:\n{}", line, text);
+        }
+
+        drop(in_flight);
+        assert!(metrics.render().contains("\nfastcgi_requests_in_flight 0\n"));
+    }
+
+    #[test]
+    fn endpoint() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.connection_opened();
+        let endpoint = MetricsEndpoint::new(metrics.clone(), "/metrics");
+        let out = call(&endpoint, "GET", "/anything");
+        assert!(out.contains("Content-Type: text/plain; version=0.0.4\r\n"), "{:?}", out);
+        assert!(out.ends_with(&format!("\r\n\r\n{}", metrics.render())), "{:?}", out);
+        assert!(out.contains("\nfastcgi_connections_opened_total 1\n"), "{:?}", out);
+    }
+
+    #[test]
+    fn endpoint_middleware() {
+        let metrics = Arc::new(Metrics::new());
+        let handler = MiddlewareStack::new()
+            .layer(MetricsEndpoint::new(metrics, "/metrics"))
+            .handler(|request: FastcgiRequest| -> Box<dyn Future<Item=(), Error=io::Error>> {
+                Box::new(request.response().send_headers().and_then(|mut body| {
+                    body.buffer.extend_from_slice(b"handler");
+                    body.finish()
+                }))
+            });
+        assert!(call(&handler, "GET", "/metrics").contains("fastcgi_requests_in_flight"));
+        assert!(call(&handler, "HEAD", "/metrics?x=1").contains("fastcgi_requests_in_flight"));
+        assert!(call(&handler, "POST", "/metrics").ends_with("\r\n\r\nhandler"));
+        assert!(call(&handler, "GET", "/metrics/more").ends_with("\r\n\r\nhandler"));
+    }
+}
@@ -0,0 +1,232 @@
+//! Layers which wrap a request handler, for things which apply to every request, like logging,
+//! authentication, or adding headers.
+
+use super::super::*;
+
+use futures::Future;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+/// Something which wraps a request handler. It gets each request before the handler does, and
+/// can change the params or body, respond by itself, or pass the request on by calling `next`.
+/// To look at or change the response on its way out, it can add a `ResponseHook` to the request.
+pub trait Middleware: Send + Sync {
+    fn call(&self, request: FastcgiRequest, next: &Arc<dyn FastcgiRequestHandler>)
+        -> Box<dyn Future<Item=(), Error=io::Error>>;
+}
+
+impl<F> Middleware for F
+    where F: Fn(FastcgiRequest, &Arc<dyn FastcgiRequestHandler>)
+        -> Box<dyn Future<Item=(), Error=io::Error>> + Send + Sync
+{
+    fn call(&self, request: FastcgiRequest, next: &Arc<dyn FastcgiRequestHandler>)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        self(request, next)
+    }
+}
+
+/// Callbacks for the response to a request, added with `FastcgiRequest::add_response_hook`.
+pub trait ResponseHook: Send + Sync {
+    /// Called with the headers of the response just before they're sent, so they can be changed.
+    /// This isn't called for local redirects, which can only have a `Location` header.
+    fn on_headers(&self, _headers: &mut HashMap<String, String>) {}
+
+    /// Called with each part of the response body as it's sent.
+    fn on_body(&self, _data: &[u8]) {}
+}
+
+/// Builds a request handler out of a stack of middleware layers around an inner handler.
+///
+/// The first layer added is the outermost one: it gets requests first, and passes them on to the
+/// second, and so on, with the last layer passing them on to the handler.
+pub struct MiddlewareStack {
+    layers: Vec<Box<dyn Middleware>>,
+}
+
+impl Default for MiddlewareStack {
+    fn default() -> MiddlewareStack {
+        MiddlewareStack::new()
+    }
+}
+
+impl MiddlewareStack {
+    pub fn new() -> MiddlewareStack {
+        MiddlewareStack {
+            layers: vec![],
+        }
+    }
+
+    /// Add a layer inside the ones added so far.
+    pub fn layer<M: Middleware + 'static>(mut self, middleware: M) -> MiddlewareStack {
+        self.layers.push(Box::new(middleware));
+        self
+    }
+
+    /// Wrap the layers around the given handler.
+    pub fn handler<H: FastcgiRequestHandler + 'static>(self, handler: H) -> Layered {
+        let inner: Arc<dyn FastcgiRequestHandler> = Arc::new(handler);
+        let handler = self.layers.into_iter()
+            .rev()
+            .fold(inner, |next, middleware| Arc::new(Link { middleware, next }));
+        Layered { handler }
+    }
+}
+
+/// One layer of a stack, wrapping the rest of it.
+struct Link {
+    middleware: Box<dyn Middleware>,
+    next: Arc<dyn FastcgiRequestHandler>,
+}
+
+impl FastcgiRequestHandler for Link {
+    fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        self.middleware.call(request, &self.next)
+    }
+}
+
+/// A request handler wrapped in middleware layers, made by `MiddlewareStack::handler`.
+pub struct Layered {
+    handler: Arc<dyn FastcgiRequestHandler>,
+}
+
+impl FastcgiRequestHandler for Layered {
+    fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        self.handler.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::response::RequestState;
+
+    use futures::{future, Stream};
+    use futures::sync::mpsc;
+    use tokio_proto::streaming::Body;
+
+    use std::sync::Mutex;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Logs requests going in and responses coming out.
+    struct Logged(&'static str, Log);
+
+    impl Middleware for Logged {
+        fn call(&self, request: FastcgiRequest, next: &Arc<dyn FastcgiRequestHandler>)
+            -> Box<dyn Future<Item=(), Error=io::Error>>
+        {
+            let (name, log) = (self.0, self.1.clone());
+            log.lock().unwrap().push(format!("{} in", name));
+            Box::new(next.call(request).map(move |()| {
+                log.lock().unwrap().push(format!("{} out", name));
+            }))
+        }
+    }
+
+    /// Responds by itself to `/blocked`.
+    struct Block;
+
+    impl Middleware for Block {
+        fn call(&self, request: FastcgiRequest, next: &Arc<dyn FastcgiRequestHandler>)
+            -> Box<dyn Future<Item=(), Error=io::Error>>
+        {
+            if request.params.get("REQUEST_URI").map(|uri| uri.as_str()) != Some("/blocked") {
+                return next.call(request);
+            }
+            let mut response = request.response();
+            response.set_status(403);
+            Box::new(response.send_headers().and_then(|body| body.finish()))
+        }
+    }
+
+    /// Adds a header to responses, takes `X-Internal` out of them, and counts the body bytes.
+    struct Headers(Arc<Mutex<usize>>);
+
+    impl ResponseHook for Headers {
+        fn on_headers(&self, headers: &mut HashMap<String, String>) {
+            headers.insert("X-Frame-Options".to_owned(), "DENY".to_owned());
+            headers.remove("X-Internal");
+        }
+
+        fn on_body(&self, data: &[u8]) {
+            *self.0.lock().unwrap() += data.len();
+        }
+    }
+
+    struct AddHeaders(Arc<Mutex<usize>>);
+
+    impl Middleware for AddHeaders {
+        fn call(&self, request: FastcgiRequest, next: &Arc<dyn FastcgiRequestHandler>)
+            -> Box<dyn Future<Item=(), Error=io::Error>>
+        {
+            request.add_response_hook(Arc::new(Headers(self.0.clone())));
+            next.call(request)
+        }
+    }
+
+    fn handler(log: Log) -> impl FastcgiRequestHandler {
+        move |request: FastcgiRequest| -> Box<dyn Future<Item=(), Error=io::Error>> {
+            log.lock().unwrap().push("handler".to_owned());
+            let mut response = request.response();
+            response.set_header("X-Internal", "secret");
+            Box::new(response.send_headers().and_then(|mut body| {
+                body.buffer.extend_from_slice(b"hello");
+                body.finish()
+            }))
+        }
+    }
+
+    /// Call the handler with a request for the path, returning the stdout of the response.
+    fn call<H: FastcgiRequestHandler>(handler: &H, path: &str) -> String {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut params = HashMap::new();
+        params.insert("REQUEST_URI".to_owned(), path.to_owned());
+        let request = FastcgiRequest::new(Role::Responder, params, Body::empty(), 1, sender,
+                                          Arc::new(RequestState::default()));
+        future::lazy(|| handler.call(request)).wait().unwrap();
+        let mut out = vec![];
+        for record in receiver.collect().wait().unwrap() {
+            if let FastcgiRecordBody::Stdout(data) = record.body {
+                out.extend_from_slice(&data);
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn order() {
+        let log = Log::default();
+        let handler = MiddlewareStack::new()
+            .layer(Logged("first", log.clone()))
+            .layer(Logged("second", log.clone()))
+            .layer(Block)
+            .layer(Logged("third", log.clone()))
+            .handler(handler(log.clone()));
+
+        assert!(call(&handler, "/").ends_with("\r\n\r\nhello"));
+        assert_eq!(*log.lock().unwrap(),
+                   vec!["first in", "second in", "third in", "handler", "third out", "second out",
+                        "first out"]);
+
+        // A layer which responds by itself doesn't pass the request on.
+        log.lock().unwrap().clear();
+        assert!(call(&handler, "/blocked").starts_with("Status: 403 Forbidden\r\n"));
+        assert_eq!(*log.lock().unwrap(), vec!["first in", "second in", "second out", "first out"]);
+    }
+
+    #[test]
+    fn response_hook() {
+        let body_bytes = Arc::new(Mutex::new(0));
+        let handler = MiddlewareStack::new()
+            .layer(AddHeaders(body_bytes.clone()))
+            .handler(handler(Log::default()));
+        let out = call(&handler, "/");
+        assert!(out.contains("X-Frame-Options: DENY\r\n"), "{:?}", out);
+        assert!(!out.contains("X-Internal"), "{:?}", out);
+        assert!(out.ends_with("\r\n\r\nhello"), "{:?}", out);
+        assert_eq!(*body_bytes.lock().unwrap(), 5);
+    }
+}
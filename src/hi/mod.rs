@@ -1,7 +1,30 @@
+pub mod access_log;
+pub mod allow_list;
+pub mod blocking;
 pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod error_page;
 pub mod handler;
+pub mod handler_pool;
+pub mod listener;
+pub mod metrics;
+pub mod middleware;
+pub mod prefork;
 pub mod proto;
+pub mod reader;
 pub mod response;
+pub mod router;
+pub mod sendfile;
+pub mod server;
 pub mod service;
+pub mod shutdown;
+pub mod status;
 pub mod stream_process;
+pub mod systemd;
+#[cfg(test)]
+mod testing;
+pub mod timeout;
+pub mod trace;
 pub mod transport;
+pub mod writer;
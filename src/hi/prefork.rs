@@ -0,0 +1,289 @@
+//! Running a server in several worker processes which share its listening sockets, like php-fpm's
+//! process manager.
+//!
+//! The master process forks the workers and then only looks after them: it restarts workers which
+//! exit, and handles these signals:
+//!
+//! * `SIGTERM` or `SIGINT`: pass `SIGTERM` on to the workers, so they shut down gracefully, and
+//!   exit once they all have;
+//! * `SIGTTIN`: start another worker;
+//! * `SIGTTOU`: stop a worker, keeping at least one;
+//! * `SIGUSR2`: log the status of the workers.
+
+use libc;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The signals the master got which it hasn't handled yet, as a bit set.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// The signals the master handles. `SIGALRM` wakes it to restart a worker after a delay.
+const SIGNALS: [libc::c_int; 7] = [
+    libc::SIGCHLD,
+    libc::SIGTERM,
+    libc::SIGINT,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+    libc::SIGUSR2,
+    libc::SIGALRM,
+];
+
+/// Workers which crash sooner than this after starting are restarted after this long, instead of
+/// straight away, so that one which can't start doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+fn got(pending: usize, signal: libc::c_int) -> bool {
+    pending & (1 << signal) != 0
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Install the master's signal handlers, and block the signals except while it waits for them.
+/// Returns the signal mask from before.
+fn install_handlers() -> io::Result<libc::sigset_t> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut blocked: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut blocked);
+        for &signal in &SIGNALS {
+            check(libc::sigaction(signal, &action, ptr::null_mut()))?;
+            libc::sigaddset(&mut blocked, signal);
+        }
+        let mut mask: libc::sigset_t = mem::zeroed();
+        check(libc::sigprocmask(libc::SIG_BLOCK, &blocked, &mut mask))?;
+        Ok(mask)
+    }
+}
+
+/// Undo `install_handlers`, in a worker.
+fn reset_handlers(mask: &libc::sigset_t) -> io::Result<()> {
+    unsafe {
+        for &signal in &SIGNALS {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+        check(libc::sigprocmask(libc::SIG_SETMASK, mask, ptr::null_mut()))
+    }
+}
+
+fn describe_exit(status: libc::c_int) -> String {
+    if libc::WIFSIGNALED(status) {
+        format!("was killed by signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("exited with status {}", libc::WEXITSTATUS(status))
+    }
+}
+
+/// A worker process.
+struct Worker {
+    started: Instant,
+    /// Whether the master told it to stop, so it shouldn't be replaced.
+    stopping: bool,
+}
+
+struct Master {
+    /// The number of workers to keep running.
+    target: usize,
+    workers: BTreeMap<libc::pid_t, Worker>,
+    /// When a worker may next be started, after one crashed at startup.
+    restart_after: Option<Instant>,
+    recycled: u64,
+    crashed: u64,
+    shutting_down: bool,
+}
+
+impl Master {
+    fn running(&self) -> Vec<libc::pid_t> {
+        self.workers.iter()
+            .filter(|&(_, worker)| !worker.stopping)
+            .map(|(pid, _)| *pid)
+            .collect()
+    }
+
+    fn stop(&mut self, pid: libc::pid_t) {
+        if let Some(worker) = self.workers.get_mut(&pid) {
+            debug!("stopping worker {}", pid);
+            worker.stopping = true;
+            if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+                warn!("failed to stop worker {}: {}", pid, io::Error::last_os_error());
+            }
+        }
+    }
+
+    /// Start or stop workers to get to the target.
+    fn scale<F>(&mut self, mask: &libc::sigset_t, run_worker: &mut F)
+        where F: FnMut() -> io::Result<()>
+    {
+        if self.shutting_down {
+            return;
+        }
+        let running = self.running();
+        for &pid in running.iter().rev().take(running.len().saturating_sub(self.target)) {
+            self.stop(pid);
+        }
+        for _ in running.len() .. self.target {
+            if let Some(after) = self.restart_after {
+                let now = Instant::now();
+                if now < after {
+                    let wait = after - now;
+                    unsafe { libc::alarm(wait.as_secs() as libc::c_uint + 1) };
+                    return;
+                }
+                self.restart_after = None;
+            }
+            match spawn(mask, run_worker) {
+                Ok(pid) => {
+                    self.workers.insert(pid, Worker { started: Instant::now(), stopping: false });
+                },
+                Err(e) => {
+                    error!("failed to start a worker: {}", e);
+                    self.restart_after = Some(Instant::now() + RESTART_DELAY);
+                },
+            }
+        }
+    }
+
+    /// Collect the workers which exited.
+    fn reap(&mut self) {
+        loop {
+            let mut status = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                return;
+            }
+            let worker = match self.workers.remove(&pid) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            let uptime = worker.started.elapsed();
+            if worker.stopping {
+                info!("worker {} stopped", pid);
+            } else if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+                info!("worker {} exited after {:?}; replacing it", pid, uptime);
+                self.recycled += 1;
+            } else {
+                warn!("worker {} {} after {:?}; restarting it", pid, describe_exit(status), uptime);
+                self.crashed += 1;
+                if uptime < RESTART_DELAY {
+                    self.restart_after = Some(Instant::now() + RESTART_DELAY);
+                }
+            }
+        }
+    }
+
+    fn shut_down(&mut self) {
+        if self.shutting_down {
+            return;
+        }
+        info!("shutting down {} workers", self.workers.len());
+        self.shutting_down = true;
+        for pid in self.running() {
+            self.stop(pid);
+        }
+    }
+
+    fn report(&self) {
+        info!("{} of {} workers running; {} replaced after exiting, {} restarted after crashing",
+              self.running().len(), self.target, self.recycled, self.crashed);
+        for (pid, worker) in &self.workers {
+            info!("worker {}: up {}s{}", pid, worker.started.elapsed().as_secs(),
+                  if worker.stopping { ", stopping" } else { "" });
+        }
+    }
+}
+
+/// Fork a worker, which runs `run_worker` and exits.
+fn spawn<F>(mask: &libc::sigset_t, run_worker: &mut F) -> io::Result<libc::pid_t>
+    where F: FnMut() -> io::Result<()>
+{
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            let code = match reset_handlers(mask).and_then(|()| run_worker()) {
+                Ok(()) => 0,
+                Err(e) => {
+                    error!("worker {} failed: {}", process::id(), e);
+                    1
+                },
+            };
+            process::exit(code);
+        },
+        pid => {
+            info!("started worker {}", pid);
+            Ok(pid)
+        },
+    }
+}
+
+/// Fork `workers` processes which each call `run_worker`, and look after them as described in the
+/// module documentation, until told to shut down. Returns once all the workers have exited.
+///
+/// A worker exits with status 0 if `run_worker` returns `Ok`, and is then replaced, as when it's
+/// recycled after serving its share of requests. If it returns an error, that's logged, and the
+/// worker exits with status 1.
+///
+/// This must be called before starting any threads or reactors, since only the calling thread
+/// carries on in the workers.
+pub fn supervise<F>(workers: usize, mut run_worker: F) -> io::Result<()>
+    where F: FnMut() -> io::Result<()>
+{
+    let mask = install_handlers()?;
+    let mut master = Master {
+        target: workers.max(1),
+        workers: BTreeMap::new(),
+        restart_after: None,
+        recycled: 0,
+        crashed: 0,
+        shutting_down: false,
+    };
+    info!("starting {} workers", master.target);
+
+    loop {
+        master.scale(&mask, &mut run_worker);
+        if master.shutting_down && master.workers.is_empty() {
+            break;
+        }
+
+        unsafe { libc::sigsuspend(&mask) };
+        let pending = PENDING.swap(0, Ordering::SeqCst);
+        if got(pending, libc::SIGCHLD) {
+            master.reap();
+        }
+        if got(pending, libc::SIGTERM) || got(pending, libc::SIGINT) {
+            master.shut_down();
+        }
+        if got(pending, libc::SIGTTIN) {
+            master.target += 1;
+            info!("scaling up to {} workers", master.target);
+        }
+        if got(pending, libc::SIGTTOU) && master.target > 1 {
+            master.target -= 1;
+            info!("scaling down to {} workers", master.target);
+        }
+        if got(pending, libc::SIGUSR2) {
+            master.report();
+        }
+    }
+
+    info!("all workers have exited");
+    reset_handlers(&mask)
+}
@@ -1,12 +1,88 @@
 use super::super::*;
 
+use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_proto::BindServer;
 use tokio_proto::streaming::multiplex::*;
 
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug, Default)]
-pub struct FastcgiProto;
+/// The FastCGI protocol for tokio-proto, optionally with metrics, shutdown and connection limits.
+///
+/// Make one with `FastcgiProto::new()`, or `FastcgiProto::default()`. This used to be a unit
+/// struct, so code which uses `FastcgiProto` itself as the value needs to call `new()` instead.
+#[derive(Debug, Default, Clone)]
+pub struct FastcgiProto {
+    metrics: Option<Arc<Metrics>>,
+    span: Option<Span>,
+    request_spans: Option<RequestSpans>,
+    shutdown: Option<Shutdown>,
+    idle_timeout: Option<(Duration, Handle)>,
+    max_requests: Option<usize>,
+}
+
+impl FastcgiProto {
+    pub fn new() -> FastcgiProto {
+        FastcgiProto::default()
+    }
+
+    /// Count connections and the records on them in the given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> FastcgiProto {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Close the connection once it's idle after the server starts shutting down.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> FastcgiProto {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Close kept connections once they've had no requests in flight for this long.
+    pub fn with_idle_timeout(mut self, timeout: Duration, handle: Handle) -> FastcgiProto {
+        self.idle_timeout = Some((timeout, handle));
+        self
+    }
+
+    /// Close kept connections once their requests finish after this many have begun on them, and
+    /// answer any more requests on them with `FCGI_OVERLOADED`.
+    pub fn with_max_requests_per_connection(mut self, max_requests: usize) -> FastcgiProto {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Record the events on the connection in the given span, made with `connection_span`.
+    /// Otherwise each connection gets a new one.
+    pub fn with_span(mut self, span: Span) -> FastcgiProto {
+        self.span = Some(span);
+        self
+    }
+
+    /// Keep the spans of the connection's requests in the given `RequestSpans`, for its service
+    /// to run them in. `bind_service` makes one for each connection.
+    pub fn with_request_spans(mut self, request_spans: RequestSpans) -> FastcgiProto {
+        self.request_spans = Some(request_spans);
+        self
+    }
+
+    /// Serve a connection with the given service, like `bind_server`, but with the connection's
+    /// span and `RequestSpans` shared with the service, so that each request runs in the same span
+    /// as the events for its records, inside the connection's span.
+    pub fn bind_service<IO, H>(&self, handle: &Handle, io: IO, service: FastcgiService<H>)
+        where IO: AsyncRead + AsyncWrite + 'static,
+              H: FastcgiRequestHandler + 'static
+    {
+        let span = self.span.clone().unwrap_or_else(connection_span);
+        let request_spans = RequestSpans::default();
+        let proto = self.clone()
+            .with_span(span.clone())
+            .with_request_spans(request_spans.clone());
+        let service = service.with_span(span).with_request_spans(request_spans);
+        proto.bind_server(handle, io, service);
+    }
+}
 
 impl<IO: AsyncRead + AsyncWrite + 'static> ServerProto<IO> for FastcgiProto {
     type Request = FastcgiRecord;
@@ -19,6 +95,23 @@ impl<IO: AsyncRead + AsyncWrite + 'static> ServerProto<IO> for FastcgiProto {
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: IO) -> Self::BindTransport {
-        Ok(FastcgiTransport::new(io))
+        let span = self.span.clone().unwrap_or_else(connection_span);
+        let mut transport = FastcgiTransport::new(io).with_span(span);
+        if let Some(ref request_spans) = self.request_spans {
+            transport = transport.with_request_spans(request_spans.clone());
+        }
+        if let Some(ref metrics) = self.metrics {
+            transport = transport.with_metrics(metrics.clone());
+        }
+        if let Some(ref shutdown) = self.shutdown {
+            transport = transport.with_shutdown(shutdown.clone());
+        }
+        if let Some((timeout, ref handle)) = self.idle_timeout {
+            transport = transport.with_idle_timeout(timeout, handle.clone());
+        }
+        if let Some(max_requests) = self.max_requests {
+            transport = transport.with_max_requests_per_connection(max_requests);
+        }
+        Ok(transport)
     }
 }
@@ -0,0 +1,162 @@
+use bytes::BytesMut;
+use futures::{Async, Poll, Stream};
+use tokio_io::AsyncRead;
+
+use std::cmp;
+use std::io::{self, BufRead, Read};
+
+/// Reads the body of a request, for use with code that wants a `Read`, `BufRead`, or `AsyncRead`.
+/// Get one with `FastcgiRequest::body_reader`, or wrap any stream of body buffers with `new`.
+///
+/// Reads can be of any size, regardless of how the body was split up into records. End of file is
+/// reported once the web server sends the end of the body, and reads fail if it aborts the request
+/// partway through, or with `UnexpectedEof` if the body stops short of its end. When no data is
+/// available yet, reads fail with `WouldBlock` and the current task is notified once there is more,
+/// so this must only be used from within a task.
+pub struct FastcgiBodyReader<S> {
+    stream: S,
+    chunk: BytesMut,
+    eof: bool,
+}
+
+impl<S: Stream<Item=BytesMut, Error=io::Error>> FastcgiBodyReader<S> {
+    pub fn new(stream: S) -> FastcgiBodyReader<S> {
+        FastcgiBodyReader {
+            stream,
+            chunk: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Get the next chunk of the body from the stream, if the current one is used up.
+    fn poll_fill(&mut self) -> Poll<(), io::Error> {
+        while self.chunk.is_empty() && !self.eof {
+            match self.stream.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    debug!("reader got {} bytes", chunk.len());
+                    self.chunk = chunk;
+                },
+                Async::Ready(None) => {
+                    debug!("reader reached the end of the body");
+                    self.eof = true;
+                },
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S: Stream<Item=BytesMut, Error=io::Error>> Read for FastcgiBodyReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let available = self.fill_buf()?;
+            let len = cmp::min(buf.len(), available.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<S: Stream<Item=BytesMut, Error=io::Error>> BufRead for FastcgiBodyReader<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if let Async::NotReady = self.poll_fill()? {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "no request body data yet"));
+        }
+        Ok(&self.chunk)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.chunk.advance(amt);
+    }
+}
+
+impl<S: Stream<Item=BytesMut, Error=io::Error>> AsyncRead for FastcgiBodyReader<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::*;
+    use super::super::response::RequestState;
+
+    use futures::{future, Future};
+    use futures::sync::mpsc;
+    use tokio_proto::streaming::Body;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Read a request's body which comes in these Stdin records, and then no more.
+    fn read(records: &[&[u8]]) -> (String, io::Result<()>) {
+        let (sender, receiver) = mpsc::channel(records.len());
+        for &data in records {
+            let record = FastcgiRecord {
+                request_id: 1,
+                body: FastcgiRecordBody::Stdin(BytesMut::from(data)),
+            };
+            sender.clone().try_send(Ok(record)).unwrap();
+        }
+        drop(sender);
+        let (response_sender, _) = mpsc::channel(1);
+        let mut request = FastcgiRequest::new(Role::Responder, HashMap::new(),
+                                              Body::from(receiver), 1, response_sender,
+                                              Arc::new(RequestState::default()));
+        let mut reader = request.body_reader();
+        future::lazy(move || {
+            // Read a few bytes at a time, to also read across the records' boundaries.
+            let mut out = vec![];
+            let mut buf = [0; 3];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => out.extend_from_slice(&buf[.. len]),
+                    Err(e) => return Ok((String::from_utf8(out).unwrap(), Err(e))),
+                }
+            }
+            assert_eq!(reader.read(&mut buf).unwrap(), 0, "read more after the end");
+            Ok::<_, ()>((String::from_utf8(out).unwrap(), Ok(())))
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn split_body() {
+        let (body, result) = read(&[b"hello", b", ", b"world", b""]);
+        assert_eq!(body, "hello, world");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn empty_body() {
+        let (body, result) = read(&[b""]);
+        assert_eq!(body, "");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn records_after_the_end() {
+        let (body, result) = read(&[b"hello", b"", b"more"]);
+        assert_eq!(body, "hello");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn body_ends_early() {
+        let (body, result) = read(&[b"hello", b", "]);
+        assert_eq!(body, "hello, ");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn no_data_yet() {
+        let (_sender, receiver) = mpsc::channel::<BytesMut>(1);
+        let mut reader = FastcgiBodyReader::new(receiver.map_err(|()| unreachable!()));
+        let result = future::lazy(move || Ok::<_, ()>(reader.read(&mut [0; 8]))).wait().unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
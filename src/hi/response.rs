@@ -1,20 +1,198 @@
 use super::super::*;
+use super::middleware::ResponseHook;
+use super::status::{is_valid_status, parse_status, status_line};
 
-use bytes::BytesMut;
-use futures::{future, Future, Sink};
+use bytes::{Bytes, BytesMut};
+use futures::{future, Async, Future, Poll, Sink};
+use futures::future::Either;
 use futures::stream::{self, Stream};
 use futures::sync::mpsc;
+use tokio_core::reactor::{Remote, Timeout};
 use tokio_proto::streaming::Body;
 
 use std::collections::HashMap;
 use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// State of a request which is shared between the service and the response objects.
+#[derive(Default)]
+pub struct RequestState {
+    app_status: AtomicU32,
+    headers_sent: AtomicBool,
+    status: AtomicU16,
+    body_bytes: AtomicU64,
+    hooks: Mutex<Vec<Arc<dyn ResponseHook>>>,
+    held: Mutex<Held>,
+    /// The reactor to run `max_delay` timers on.
+    remote: Option<Remote>,
+}
+
+/// Body data held back by `FastcgiBodyResponse::flush` under its write policy.
+#[derive(Default)]
+struct Held {
+    data: Vec<u8>,
+    /// For sending the data if the response doesn't. This is only kept while there's data, so that
+    /// it doesn't hold the response open.
+    sender: Option<mpsc::Sender<FastcgiRecord>>,
+    /// Bumped whenever the data is taken, so that a timer armed for data which has already been
+    /// sent does nothing.
+    generation: u64,
+}
+
+impl Held {
+    fn take(&mut self) -> (Vec<u8>, Option<mpsc::Sender<FastcgiRecord>>) {
+        self.generation += 1;
+        (mem::take(&mut self.data), self.sender.take())
+    }
+}
+
+impl RequestState {
+    pub fn new(remote: Remote) -> RequestState {
+        RequestState {
+            remote: Some(remote),
+            ..RequestState::default()
+        }
+    }
+
+    pub fn app_status(&self) -> u32 {
+        self.app_status.load(Ordering::SeqCst)
+    }
+
+    pub fn set_app_status(&self, app_status: u32) {
+        self.app_status.store(app_status, Ordering::SeqCst);
+    }
+
+    pub fn headers_sent(&self) -> bool {
+        self.headers_sent.load(Ordering::SeqCst)
+    }
+
+    pub fn set_headers_sent(&self) {
+        self.headers_sent.store(true, Ordering::SeqCst);
+    }
+
+    /// The HTTP status sent, or `None` if no headers have been sent, or for a local redirect.
+    pub fn status(&self) -> Option<u16> {
+        match self.status.load(Ordering::SeqCst) {
+            0 => None,
+            status => Some(status),
+        }
+    }
+
+    pub fn set_status(&self, status: u16) {
+        self.status.store(status, Ordering::SeqCst);
+    }
+
+    /// The number of bytes of the response body sent so far.
+    pub fn body_bytes(&self) -> u64 {
+        self.body_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn add_body_bytes(&self, len: usize) {
+        self.body_bytes.fetch_add(len as u64, Ordering::SeqCst);
+    }
+
+    pub fn add_hook(&self, hook: Arc<dyn ResponseHook>) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    pub fn on_headers(&self, headers: &mut HashMap<String, String>) {
+        for hook in self.hooks.lock().unwrap().iter() {
+            hook.on_headers(headers);
+        }
+    }
+
+    pub fn on_body(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.add_body_bytes(data.len());
+        for hook in self.hooks.lock().unwrap().iter() {
+            hook.on_body(data);
+        }
+    }
+}
+
+/// Controls how eagerly `FastcgiBodyResponse::flush` sends data to the web server, to avoid sending
+/// lots of tiny records when a handler flushes often.
+///
+/// A flush sends the buffered data if there is at least `min_bytes` of it, counting what earlier
+/// flushes held back. Otherwise the data is taken out of the buffer and held back, to be merged
+/// with whatever is flushed next, but sent no later than `max_delay` after it was first held back,
+/// even if nothing else is. `flush_now` and `finish` always send everything.
+///
+/// The default policy sends on every flush.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WritePolicy {
+    pub min_bytes: usize,
+    pub max_delay: Option<Duration>,
+}
+
+/// Split the data up into as many records of the given type as needed to stay within the maximum
+/// record length.
+pub fn chunk_records(request_id: u16, data: &[u8], body: fn(BytesMut) -> FastcgiRecordBody)
+    -> Vec<FastcgiRecord>
+{
+    data.chunks(0xFFFF)
+        .map(|slice| {
+            FastcgiRecord {
+                request_id,
+                body: body(BytesMut::from(slice)),
+            }
+        })
+        .collect()
+}
+
+/// The buffers of a request body, up to the empty one which ends it. The body failing to arrive in
+/// full, with the record stream ending before that, is an `UnexpectedEof` error.
+struct StdinBody<S> {
+    inner: S,
+    ended: bool,
+}
+
+impl<S: Stream<Item=BytesMut, Error=io::Error>> Stream for StdinBody<S> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        if self.ended {
+            return Ok(Async::Ready(None));
+        }
+        match self.inner.poll()? {
+            Async::Ready(Some(ref buf)) if buf.is_empty() => {
+                self.ended = true;
+                Ok(Async::Ready(None))
+            },
+            Async::Ready(Some(buf)) => Ok(Async::Ready(Some(buf))),
+            Async::Ready(None) => {
+                let msg = "the request body ended without its final empty Stdin record";
+                error!("{}", msg);
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg))
+            },
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+pub fn broken_pipe<E: ::std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, e)
+}
 
 pub struct FastcgiRequest {
     pub role: Role,
     pub params: HashMap<String, String>,
-    pub body: Box<dyn Stream<Item=BytesMut, Error=io::Error>>,
+    pub body: Box<dyn Stream<Item=BytesMut, Error=io::Error> + Send>,
+    /// Values captured from the request path by a `Router` pattern, by name.
+    pub path_params: HashMap<String, String>,
+    /// Trace context from the `HTTP_TRACEPARENT` param, if the service was set to look for it
+    /// with `FastcgiService::with_trace_context`.
+    pub trace_parent: Option<TraceParent>,
     request_id: u16,
     sender: mpsc::Sender<FastcgiRecord>,
+    state: Arc<RequestState>,
+    write_policy: WritePolicy,
 }
 
 impl FastcgiRequest {
@@ -24,33 +202,62 @@ impl FastcgiRequest {
         body: Body<FastcgiRecord, io::Error>,
         request_id: u16,
         sender: mpsc::Sender<FastcgiRecord>,
+        state: Arc<RequestState>,
         ) -> FastcgiRequest
     {
-        // The body stream is expected to consist only of Stdin records. Extract the buffers from
-        // these and give the handler a stream of those instead. Anything other than a Stdin record
-        // results in an error.
+        // The body stream is expected to consist only of Stdin records, ending with an empty one.
+        // Extract the buffers from these and give the handler a stream of those instead. The web
+        // server aborting the request, or anything other than a Stdin record, results in an error.
         let buf_stream = body.and_then(|record| {
             match record.body {
                 FastcgiRecordBody::Stdin(buf) => Ok(buf),
+                FastcgiRecordBody::AbortRequest => {
+                    let msg = "request aborted by the web server while reading the body";
+                    warn!("{}", msg);
+                    Err(io::Error::new(io::ErrorKind::ConnectionAborted, msg))
+                },
                 _ => {
                     let msg = format!("unexpected request body record {:?}", record.body);
                     error!("{}", msg);
                     Err(io::Error::new(io::ErrorKind::InvalidData, msg))
                 }
             }
-        }).take_while(|buf| Ok(!buf.is_empty())); // empty Stdin record signals the end.
+        });
 
         FastcgiRequest {
             role,
             params,
-            body: Box::new(buf_stream),
+            body: Box::new(StdinBody { inner: buf_stream, ended: false }),
+            path_params: HashMap::new(),
+            trace_parent: None,
             request_id,
             sender,
+            state,
+            write_policy: WritePolicy::default(),
         }
     }
 
+    /// Set the write policy for responses to this request.
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    /// Add callbacks for the response to this request, so that middleware can see or change it on
+    /// its way out.
+    pub fn add_response_hook(&self, hook: Arc<dyn ResponseHook>) {
+        self.state.add_hook(hook);
+    }
+
+    /// Take the body stream as a `Read` / `AsyncRead`. This leaves `body` empty.
+    pub fn body_reader(&mut self)
+        -> FastcgiBodyReader<Box<dyn Stream<Item=BytesMut, Error=io::Error> + Send>>
+    {
+        FastcgiBodyReader::new(mem::replace(&mut self.body, Box::new(stream::empty())))
+    }
+
     pub fn response(&self) -> FastcgiHeadersResponse {
-        FastcgiHeadersResponse::new(self.request_id, self.sender.clone())
+        FastcgiHeadersResponse::new(
+            self.request_id, self.sender.clone(), self.state.clone(), self.write_policy)
     }
 }
 
@@ -58,10 +265,20 @@ pub struct FastcgiHeadersResponse {
     sender: mpsc::Sender<FastcgiRecord>,
     request_id: u16,
     headers: HashMap<String, String>,
+    /// Headers added with `add_header` when one with the same name was already set.
+    repeated: Vec<(String, String)>,
+    state: Arc<RequestState>,
+    write_policy: WritePolicy,
 }
 
 impl FastcgiHeadersResponse {
-    fn new(request_id: u16, sender: mpsc::Sender<FastcgiRecord>) -> FastcgiHeadersResponse {
+    pub fn new(
+        request_id: u16,
+        sender: mpsc::Sender<FastcgiRecord>,
+        state: Arc<RequestState>,
+        write_policy: WritePolicy,
+        ) -> FastcgiHeadersResponse
+    {
         let mut headers = HashMap::new();
         headers.insert(
             "X-Powered-By".to_owned(),
@@ -70,9 +287,17 @@ impl FastcgiHeadersResponse {
             sender,
             request_id,
             headers,
+            repeated: vec![],
+            state,
+            write_policy,
         }
     }
 
+    /// Set the write policy for the body of the response.
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
     pub fn headers(&self) -> &HashMap<String, String> {
         &self.headers
     }
@@ -82,17 +307,107 @@ impl FastcgiHeadersResponse {
     }
 
     pub fn set_header<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
-        self.headers.insert(name.into(), value.into());
+        let name = name.into();
+        self.repeated.retain(|(key, _)| *key != name);
+        self.headers.insert(name, value.into());
+    }
+
+    /// Add a header, keeping any already set with the same name, as for `Set-Cookie`. Only the
+    /// first value of each name is in `headers`; the rest are sent after those, in the order
+    /// they were added.
+    pub fn add_header<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
+        let name = name.into();
+        if self.has_header(&name) {
+            self.repeated.push((name, value.into()));
+        } else {
+            self.headers.insert(name, value.into());
+        }
     }
 
     pub fn clear_header(&mut self, name: &str) {
         self.headers.remove(name);
+        self.repeated.retain(|(key, _)| key != name);
+    }
+
+    /// Set the HTTP status of the response, using the standard reason phrase for the code. This
+    /// replaces any `Status` header set previously. Codes outside 100 to 999 aren't valid, and are
+    /// logged and sent as 500 instead.
+    pub fn set_status(&mut self, code: u16) {
+        let code = if is_valid_status(code) {
+            code
+        } else {
+            error!("invalid HTTP status {}; sending 500 instead", code);
+            500
+        };
+        self.headers.retain(|name, _| !name.eq_ignore_ascii_case("Status"));
+        self.headers.insert("Status".to_owned(), status_line(code));
+    }
+
+    /// Get the HTTP status code set on the response, if any.
+    pub fn status(&self) -> Option<u16> {
+        self.get_header("Status").and_then(parse_status)
+    }
+
+    /// Get the value of a header, matching its name case-insensitively.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
     }
 
-    pub fn send_headers(self) -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error> + Send> {
+    fn has_header(&self, name: &str) -> bool {
+        self.get_header(name).is_some()
+    }
+
+    pub fn send_headers(mut self)
+        -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error> + Send>
+    {
         debug!("sending headers");
+
+        self.state.on_headers(&mut self.headers);
+
+        // CGI requires every response to be either a document (with a Content-Type) or a
+        // redirect (with a Location).
+        if !self.has_header("Content-Type") && !self.has_header("Location") {
+            warn!("response has neither Content-Type nor Location; using text/plain");
+            self.headers.insert("Content-Type".to_owned(), "text/plain".to_owned());
+        }
+
+        // The Status header has to come first, and there can be only one of it.
+        let (status, headers): (Vec<_>, Vec<_>) = self.headers.drain()
+            .chain(self.repeated.drain(..))
+            .partition(|(name, _)| name.eq_ignore_ascii_case("Status"));
+        if status.len() > 1 {
+            let msg = format!("response has {} Status headers", status.len());
+            error!("{}", msg);
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+
+        // Without a Status header, CGI says a Location with a URL is a 302 redirect.
+        let code = match status.first() {
+            Some((_, value)) => match parse_status(value) {
+                Some(code) => code,
+                None => {
+                    let msg = format!("invalid Status header {:?}", value);
+                    error!("{}", msg);
+                    return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+                },
+            },
+            None if headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Location")) => 302,
+            None => 200,
+        };
+        self.state.set_status(code);
+
+        let headers = status.into_iter().chain(headers).collect();
+        self.write_headers(headers)
+    }
+
+    /// Send exactly the given headers, in order.
+    fn write_headers(self, headers: Vec<(String, String)>)
+        -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error> + Send>
+    {
         let mut out = BytesMut::new();
-        for (ref key, ref value) in self.headers {
+        for (ref key, ref value) in headers {
             out.extend_from_slice(key.as_bytes());
             out.extend_from_slice(b": ");
             out.extend_from_slice(value.as_bytes());
@@ -106,72 +421,486 @@ impl FastcgiHeadersResponse {
         };
 
         let request_id = self.request_id;
+        let state = self.state;
+        let write_policy = self.write_policy;
+        state.set_headers_sent();
 
         Box::new(self.sender
             .send(record)
-            .map(move |sender| FastcgiBodyResponse::new(request_id, sender))
-            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)))
+            .map(move |sender| FastcgiBodyResponse::new(request_id, sender, state, write_policy))
+            .map_err(broken_pipe))
+    }
+
+    /// Send a CGI local redirect: the web server serves the given path (which must start with a
+    /// `/`) in place of this response, without the client seeing a redirect. This sends nothing
+    /// but the `Location` header, as CGI requires, so any headers set on this response are
+    /// dropped. The response is ended.
+    pub fn local_redirect<S: Into<String>>(self, path: S)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        let path = path.into();
+        if !path.starts_with('/') || path.contains(['\r', '\n']) {
+            let msg = format!("invalid local redirect path {:?}", path);
+            error!("{}", msg);
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        debug!("local redirect to {:?}", path);
+        Box::new(self.write_headers(vec![("Location".to_owned(), path)])
+            .and_then(|body| body.finish()))
+    }
+
+    /// Send a redirect to the client, with the given 3xx status and absolute URL. The body
+    /// response returned has a short HTML document linking to the URL already in its buffer, as
+    /// recommended for clients which don't follow redirects; it can be cleared or added to before
+    /// finishing it.
+    pub fn client_redirect<S: Into<String>>(mut self, status: u16, location: S)
+        -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error>>
+    {
+        let location = location.into();
+        let has_scheme = location.find(':')
+            .map(|i| i > 0 && location[..i].chars()
+                 .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.'))
+            .unwrap_or(false);
+        if status / 100 != 3 || !has_scheme || location.contains(['\r', '\n']) {
+            let msg = format!("invalid client redirect: {} to {:?}", status, location);
+            error!("{}", msg);
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        debug!("client redirect ({}) to {:?}", status, location);
+
+        let escaped = location
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;");
+        let document = format!(
+            "<!DOCTYPE html>\n<html><head><title>{status}</title></head>\n\
+             <body><p>The document has moved <a href=\"{url}\">here</a>.</p></body></html>\n",
+            status = status_line(status), url = escaped);
+
+        self.set_status(status);
+        self.set_header("Location", location);
+        if !self.has_header("Content-Type") {
+            self.set_header("Content-Type", "text/html");
+        }
+        Box::new(self.send_headers().map(move |mut body| {
+            body.buffer.extend_from_slice(document.as_bytes());
+            body
+        }))
     }
 }
 
+enum SendStreamError {
+    Body(io::Error),
+    Send(io::Error),
+}
+
 pub struct FastcgiBodyResponse {
     // this is an `Option` just so we can implement `Drop`.
     sender: Option<mpsc::Sender<FastcgiRecord>>,
     request_id: u16,
+    state: Arc<RequestState>,
+    write_policy: WritePolicy,
     pub buffer: Vec<u8>,
 }
 
 impl FastcgiBodyResponse {
-    fn new(request_id: u16, sender: mpsc::Sender<FastcgiRecord>) -> FastcgiBodyResponse {
+    fn new(
+        request_id: u16,
+        sender: mpsc::Sender<FastcgiRecord>,
+        state: Arc<RequestState>,
+        write_policy: WritePolicy,
+        ) -> FastcgiBodyResponse
+    {
         FastcgiBodyResponse {
             sender: Some(sender),
             request_id,
+            state,
+            write_policy,
             buffer: Vec::new(),
         }
     }
 
+    /// Set the write policy for the rest of the body.
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    /// Send the buffered data, if the write policy says it's time to.
     pub fn flush(mut self) -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error>> {
-        debug!("flushing body of {} bytes", self.buffer.len());
+        let held = self.state.held.lock().unwrap().data.len();
+        if held + self.buffer.len() < self.write_policy.min_bytes {
+            self.hold();
+            return Box::new(future::ok(self));
+        }
+        self.flush_now()
+    }
+
+    /// Move the buffer to the held back data. If there wasn't any yet, arm a timer to send it at
+    /// the policy's `max_delay`.
+    fn hold(&mut self) {
+        let mut held = self.state.held.lock().unwrap();
+        let was_empty = held.data.is_empty();
+        held.data.append(&mut self.buffer);
+        debug!("holding back {} bytes", held.data.len());
+        if !was_empty || held.data.is_empty() {
+            return;
+        }
+        held.sender = self.sender.clone();
+        let (delay, remote) = match (self.write_policy.max_delay, self.state.remote.as_ref()) {
+            (Some(delay), Some(remote)) => (delay, remote),
+            _ => return,
+        };
+        let generation = held.generation;
+        let state = self.state.clone();
         let request_id = self.request_id;
+        remote.spawn(move |handle| match Timeout::new(delay, handle) {
+            Ok(timeout) => Either::A(timeout.then(move |_| {
+                send_held(&state, Some(generation), request_id);
+                Ok(())
+            })),
+            Err(e) => {
+                error!("failed to arm the write policy timer: {}", e);
+                Either::B(future::ok(()))
+            },
+        });
+    }
 
-        let buffer = std::mem::replace(&mut self.buffer, vec![]);
+    /// Take the data held back by `flush`, followed by the buffer.
+    fn take_buffer(&mut self) -> Vec<u8> {
+        let (mut data, _sender) = self.state.held.lock().unwrap().take();
+        data.append(&mut self.buffer);
+        data
+    }
 
-        let records = buffer
-            .chunks(0xFFFF)
-            .map(move |slice| {
-                FastcgiRecord {
-                    request_id,
-                    body: FastcgiRecordBody::Stdout(BytesMut::from(slice.to_vec())),
-                }
-            })
-            .collect::<Vec<_>>();
+    /// Send the buffered data right away, regardless of the write policy.
+    pub fn flush_now(mut self) -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error>> {
+        let buffer = self.take_buffer();
+        debug!("flushing {} bytes of body", buffer.len());
+        let request_id = self.request_id;
+        let state = self.state.clone();
+        let write_policy = self.write_policy;
+
+        state.on_body(&buffer);
+        let records = chunk_records(request_id, &buffer, FastcgiRecordBody::Stdout);
 
         Box::new(self.sender
             .take()
             .unwrap()
             .send_all(stream::iter_ok(records))
             .map(move |(stream, _sink)| {
-                FastcgiBodyResponse::new(request_id, stream)
+                FastcgiBodyResponse::new(request_id, stream, state, write_policy)
+            })
+            .map_err(broken_pipe))
+    }
+
+    /// Send everything the given stream yields as the response body, and resolve once the stream
+    /// ends. Anything in the buffer is sent first. The stream is only polled as fast as the web
+    /// server accepts the records.
+    ///
+    /// If the stream fails, the error is reported to the web server on stderr and with a non-zero
+    /// app status, and then the returned future fails with it.
+    pub fn send_stream<S>(mut self, body: S)
+        -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error>>
+        where S: Stream<Item=Bytes, Error=io::Error> + 'static
+    {
+        debug!("sending body stream");
+        let request_id = self.request_id;
+        let state = self.state.clone();
+        let write_policy = self.write_policy;
+        let sender = self.sender.take().unwrap();
+        let error_sender = sender.clone();
+        let hook_state = state.clone();
+
+        let buffer = self.take_buffer();
+        let records = stream::once(Ok(Bytes::from(buffer)))
+            .chain(body)
+            .map_err(SendStreamError::Body)
+            .map(move |bytes| {
+                hook_state.on_body(&bytes);
+                stream::iter_ok(chunk_records(request_id, &bytes, FastcgiRecordBody::Stdout))
             })
-            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)))
+            .flatten();
+
+        Box::new(sender
+            .sink_map_err(|e| SendStreamError::Send(broken_pipe(e)))
+            .send_all(records)
+            .then(move |result| -> Box<dyn Future<Item=FastcgiBodyResponse, Error=io::Error>> {
+                match result {
+                    Ok((sender, _records)) => {
+                        debug!("body stream finished");
+                        Box::new(future::ok(FastcgiBodyResponse::new(
+                            request_id, sender.into_inner(), state, write_policy)))
+                    },
+                    Err(SendStreamError::Send(e)) => Box::new(future::err(e)),
+                    Err(SendStreamError::Body(e)) => {
+                        error!("error in response body stream: {}", e);
+                        state.set_app_status(1);
+                        let msg = format!("error in response body stream: {}\n", e);
+                        let records = chunk_records(
+                            request_id, msg.as_bytes(), FastcgiRecordBody::Stderr);
+                        Box::new(error_sender
+                            .send_all(stream::iter_ok(records))
+                            .then(move |_| Err(e)))
+                    }
+                }
+            }))
+    }
+
+    /// Turn this into a `Write` / `AsyncWrite` for the rest of the body. Anything in the buffer
+    /// gets written first.
+    pub fn into_writer(mut self) -> FastcgiBodyWriter {
+        let buffer = self.take_buffer();
+        let sender = self.sender.take().unwrap();
+        FastcgiBodyWriter::new(self.request_id, sender, self.state.clone(), buffer)
     }
 
     pub fn finish(self) -> Box<dyn Future<Item=(), Error=io::Error>> {
         debug!("finishing body");
-        if self.buffer.is_empty() {
+        if self.buffer.is_empty() && self.state.held.lock().unwrap().data.is_empty() {
             Box::new(future::ok(()))
         } else {
-            Box::new(self.flush()
+            Box::new(self.flush_now()
                 .map(|_| ()))
         }
     }
 }
 
+/// Send the data held back by `flush`, unless it was taken since the given generation.
+///
+/// This doesn't wait for room in the channel: a new clone of a sender always has room for one
+/// record. The data goes in ahead of anything sent after the lock is released, and there's less
+/// of it than the write policy's `min_bytes`, so this doesn't undo the backpressure by much.
+fn send_held(state: &RequestState, generation: Option<u64>, request_id: u16) {
+    let mut held = state.held.lock().unwrap();
+    if generation.map(|g| g != held.generation).unwrap_or(false) {
+        return;
+    }
+    let (data, sender) = held.take();
+    let sender = match sender {
+        Some(ref sender) if !data.is_empty() => sender,
+        _ => return,
+    };
+    debug!("sending {} bytes of body held back by the write policy", data.len());
+    state.on_body(&data);
+    for record in chunk_records(request_id, &data, FastcgiRecordBody::Stdout) {
+        if sender.clone().try_send(record).is_err() {
+            debug!("response closed before the held back body was sent");
+            return;
+        }
+    }
+}
+
 impl Drop for FastcgiBodyResponse {
     fn drop(&mut self) {
         if !self.buffer.is_empty() {
             warn!("FastcgiBodyResponse dropped with un-flushed buffer of {} bytes!",
                   self.buffer.len());
         }
+        // What was flushed should still get sent, even though the write policy held it back.
+        send_held(&self.state, None, self.request_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Async;
+    use tokio_core::reactor::Core;
+
+    fn body(core: &Core, policy: WritePolicy)
+        -> (FastcgiBodyResponse, mpsc::Receiver<FastcgiRecord>)
+    {
+        let (sender, receiver) = mpsc::channel(16);
+        let state = Arc::new(RequestState::new(core.remote()));
+        (FastcgiBodyResponse::new(1, sender, state, policy), receiver)
+    }
+
+    fn headers(core: &Core)
+        -> (FastcgiHeadersResponse, mpsc::Receiver<FastcgiRecord>, Arc<RequestState>)
+    {
+        let (sender, receiver) = mpsc::channel(16);
+        let state = Arc::new(RequestState::new(core.remote()));
+        let policy = WritePolicy::default();
+        let response = FastcgiHeadersResponse::new(1, sender, state.clone(), policy);
+        (response, receiver, state)
+    }
+
+    /// Add the data to the buffer and flush it.
+    fn flush(core: &mut Core, mut response: FastcgiBodyResponse, data: &str)
+        -> FastcgiBodyResponse
+    {
+        response.buffer.extend_from_slice(data.as_bytes());
+        core.run(response.flush()).unwrap()
+    }
+
+    /// The body records received so far.
+    fn received(receiver: &mut mpsc::Receiver<FastcgiRecord>) -> Vec<String> {
+        future::lazy(|| {
+            let mut out = vec![];
+            while let Ok(Async::Ready(Some(record))) = receiver.poll() {
+                match record.body {
+                    FastcgiRecordBody::Stdout(data) => {
+                        out.push(String::from_utf8(data.to_vec()).unwrap());
+                    },
+                    body => panic!("unexpected record {:?}", body),
+                }
+            }
+            Ok::<_, ()>(out)
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn default_policy() {
+        let mut core = Core::new().unwrap();
+        let (response, mut receiver) = body(&core, WritePolicy::default());
+        let response = flush(&mut core, response, "abc");
+        assert_eq!(received(&mut receiver), vec!["abc"]);
+        drop(response);
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn min_bytes() {
+        let mut core = Core::new().unwrap();
+        let policy = WritePolicy { min_bytes: 10, max_delay: None };
+        let (response, mut receiver) = body(&core, policy);
+        let response = flush(&mut core, response, "abc");
+        let response = flush(&mut core, response, "defgh");
+        assert!(received(&mut receiver).is_empty());
+        let response = flush(&mut core, response, "ij");
+        assert_eq!(received(&mut receiver), vec!["abcdefghij"]);
+        let response = flush(&mut core, response, "k");
+        assert!(received(&mut receiver).is_empty());
+        core.run(response.finish()).unwrap();
+        assert_eq!(received(&mut receiver), vec!["k"]);
+    }
+
+    #[test]
+    fn flush_now() {
+        let mut core = Core::new().unwrap();
+        let policy = WritePolicy { min_bytes: 10, max_delay: None };
+        let (response, mut receiver) = body(&core, policy);
+        let mut response = flush(&mut core, response, "abc");
+        response.buffer.extend_from_slice(b"def");
+        let response = core.run(response.flush_now()).unwrap();
+        assert_eq!(received(&mut receiver), vec!["abcdef"]);
+        drop(response);
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn max_delay() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let delay = Duration::from_millis(50);
+        let policy = WritePolicy { min_bytes: 10, max_delay: Some(delay) };
+        let (response, mut receiver) = body(&core, policy);
+        let response = flush(&mut core, response, "abc");
+        let response = flush(&mut core, response, "def");
+        assert!(received(&mut receiver).is_empty());
+        core.run(Timeout::new(delay * 2, &handle).unwrap()).unwrap();
+        assert_eq!(received(&mut receiver), vec!["abcdef"]);
+
+        // Once the data has been sent some other way, its timer does nothing.
+        let response = flush(&mut core, response, "ghi");
+        let response = flush(&mut core, response, "jklmnop");
+        assert_eq!(received(&mut receiver), vec!["ghijklmnop"]);
+        let response = flush(&mut core, response, "n");
+        core.run(Timeout::new(delay * 2, &handle).unwrap()).unwrap();
+        assert_eq!(received(&mut receiver), vec!["n"]);
+        drop(response);
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn dropped_while_holding() {
+        let mut core = Core::new().unwrap();
+        let policy = WritePolicy { min_bytes: 10, max_delay: None };
+        let (response, mut receiver) = body(&core, policy);
+        let response = flush(&mut core, response, "abc");
+        drop(response);
+        assert_eq!(received(&mut receiver), vec!["abc"]);
+    }
+
+    #[test]
+    fn dropped_while_holding_after_close() {
+        let mut core = Core::new().unwrap();
+        let policy = WritePolicy { min_bytes: 10, max_delay: None };
+        let (response, receiver) = body(&core, policy);
+        let response = flush(&mut core, response, "abc");
+        let state = response.state.clone();
+        // Sending what's held fails once the request is over, and the data is just dropped.
+        drop(receiver);
+        drop(response);
+        assert!(state.held.lock().unwrap().data.is_empty());
+        assert!(state.held.lock().unwrap().sender.is_none());
+    }
+
+    #[test]
+    fn local_redirect() {
+        let mut core = Core::new().unwrap();
+        let (mut response, mut receiver, state) = headers(&core);
+        response.set_status(200);
+        response.set_header("Content-Type", "text/plain");
+        core.run(response.local_redirect("/other?a=1")).unwrap();
+        // Only the Location header, and no body.
+        assert_eq!(received(&mut receiver).concat(), "Location: /other?a=1\r\n\r\n");
+        assert_eq!(state.status(), None);
+    }
+
+    #[test]
+    fn invalid_local_redirect() {
+        let core = Core::new().unwrap();
+        for path in &["other", "http://example.com/", "/other\r\nStatus: 200"] {
+            let (response, _receiver, state) = headers(&core);
+            let error = response.local_redirect(*path).wait().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", path);
+            assert!(!state.headers_sent());
+        }
+    }
+
+    #[test]
+    fn client_redirect() {
+        let mut core = Core::new().unwrap();
+        let (response, mut receiver, state) = headers(&core);
+        let body = core.run(response.client_redirect(301, "https://example.com/?a=1&b=<2>"))
+            .unwrap();
+        core.run(body.finish()).unwrap();
+        let out = received(&mut receiver).concat();
+        assert!(out.starts_with("Status: 301 Moved Permanently\r\n"), "{:?}", out);
+        assert!(out.contains("\r\nLocation: https://example.com/?a=1&b=<2>\r\n"), "{:?}", out);
+        assert!(out.contains("\r\nContent-Type: text/html\r\n"), "{:?}", out);
+        assert!(out.contains("<a href=\"https://example.com/?a=1&amp;b=&lt;2&gt;\">"), "{:?}", out);
+        assert_eq!(state.status(), Some(301));
+    }
+
+    #[test]
+    fn invalid_client_redirect() {
+        let core = Core::new().unwrap();
+        for &(status, location) in &[(200, "https://example.com/"), (302, "/relative"),
+                                     (302, "https://example.com/\r\nX-Header: 1")] {
+            let (response, _receiver, state) = headers(&core);
+            let error = match response.client_redirect(status, location).wait() {
+                Ok(_) => panic!("redirected with {} to {:?}", status, location),
+                Err(error) => error,
+            };
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{} {:?}", status, location);
+            assert!(!state.headers_sent());
+        }
+    }
+
+    #[test]
+    fn location_without_status() {
+        let mut core = Core::new().unwrap();
+        let (mut response, mut receiver, state) = headers(&core);
+        response.set_header("Location", "https://example.com/");
+        core.run(response.send_headers()).unwrap();
+        // The web server makes it a 302, as CGI says.
+        let out = received(&mut receiver).concat();
+        assert!(!out.contains("Status:"), "{:?}", out);
+        assert!(out.contains("Location: https://example.com/\r\n"), "{:?}", out);
+        assert_eq!(state.status(), Some(302));
     }
 }
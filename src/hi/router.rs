@@ -0,0 +1,310 @@
+//! Dispatching requests to handlers by method and path.
+
+use super::super::*;
+
+use futures::Future;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Route {
+    /// `None` matches any method.
+    method: Option<String>,
+    segments: Vec<Segment>,
+    handler: Box<dyn FastcgiRequestHandler>,
+}
+
+impl Route {
+    /// Match the route's pattern against the segments of a path, returning the captured values.
+    fn matches(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        let mut captures = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match *segment {
+                Segment::Literal(ref literal) => {
+                    if path.get(i) != Some(&literal.as_str()) {
+                        return None;
+                    }
+                },
+                Segment::Param(ref name) => {
+                    captures.insert(name.clone(), (*path.get(i)?).to_owned());
+                },
+                Segment::Wildcard(ref name) => {
+                    captures.insert(name.clone(), path[i.min(path.len())..].join("/"));
+                    return Some(captures);
+                },
+            }
+        }
+        if path.len() == self.segments.len() {
+            Some(captures)
+        } else {
+            None
+        }
+    }
+}
+
+/// A request handler which passes each request on to one of a set of handlers, chosen by the
+/// request's method and path. The path is `SCRIPT_NAME` followed by `PATH_INFO`.
+///
+/// Patterns are made of `/`-separated segments, each of which is one of:
+///
+/// * a literal, which must match the path segment exactly;
+/// * `:name`, which matches any one segment;
+/// * `*name`, which matches the rest of the path, including none of it. This can only be last.
+///
+/// The values matched by named segments are put in the request's `path_params` for the handler.
+/// Empty segments are ignored, so a trailing slash makes no difference.
+///
+/// Routes are tried in the order they were added, and the first one that matches is used. `GET`
+/// routes also match `HEAD` requests. If no route's pattern matches, the response is a 404; if
+/// some do, but not for the request's method, it is a 405 with an `Allow` header.
+pub struct Router {
+    routes: Vec<Route>,
+    error_page: ErrorPage,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: vec![],
+            error_page: ErrorPage::default(),
+        }
+    }
+
+    /// Add a route for the given method and path pattern.
+    ///
+    /// Panics if the pattern has a wildcard segment anywhere but at the end.
+    pub fn route<H>(self, method: &str, pattern: &str, handler: H) -> Router
+        where H: FastcgiRequestHandler + 'static
+    {
+        self.add(Some(method.to_ascii_uppercase()), pattern, Box::new(handler))
+    }
+
+    /// Add a route for the given path pattern which matches requests with any method.
+    ///
+    /// Panics if the pattern has a wildcard segment anywhere but at the end.
+    pub fn any<H>(self, pattern: &str, handler: H) -> Router
+        where H: FastcgiRequestHandler + 'static
+    {
+        self.add(None, pattern, Box::new(handler))
+    }
+
+    /// Set the page sent as the body of 404 and 405 responses.
+    pub fn with_error_page(mut self, page: ErrorPage) -> Router {
+        self.error_page = page;
+        self
+    }
+
+    fn add(mut self, method: Option<String>, pattern: &str, handler: Box<dyn FastcgiRequestHandler>)
+        -> Router
+    {
+        let parts: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+        let segments = parts.iter()
+            .enumerate()
+            .map(|(i, part)| {
+                if let Some(name) = part.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = part.strip_prefix('*') {
+                    if i + 1 != parts.len() {
+                        panic!("wildcard segment {:?} is not at the end of route pattern {:?}",
+                               part, pattern);
+                    }
+                    Segment::Wildcard(name.to_owned())
+                } else {
+                    Segment::Literal((*part).to_owned())
+                }
+            })
+            .collect();
+        self.routes.push(Route { method, segments, handler });
+        self
+    }
+
+    fn error_response(&self, request: &FastcgiRequest, status: u16, allow: Option<String>)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        let mut response = request.response();
+        if let Some(allow) = allow {
+            response.set_header("Allow", allow);
+        }
+        self.error_page.send(response, status)
+    }
+}
+
+/// The path a request is for: `SCRIPT_NAME` followed by `PATH_INFO`, or if neither is set, the
+/// path part of `REQUEST_URI`.
+pub fn request_path(params: &HashMap<String, String>) -> String {
+    let script_name = params.get("SCRIPT_NAME").map(|s| s.as_str()).unwrap_or("");
+    let path_info = params.get("PATH_INFO").map(|s| s.as_str()).unwrap_or("");
+    if script_name.is_empty() && path_info.is_empty() {
+        params.get("REQUEST_URI")
+            .map(|uri| uri.split('?').next().unwrap_or("").to_owned())
+            .unwrap_or_default()
+    } else {
+        format!("{}{}", script_name, path_info)
+    }
+}
+
+impl FastcgiRequestHandler for Router {
+    fn call(&self, mut request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let path = request_path(&request.params);
+        let method = request.params.get("REQUEST_METHOD")
+            .map(|method| method.to_ascii_uppercase())
+            .unwrap_or_default();
+        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let mut found = None;
+        let mut allowed = BTreeSet::new();
+        for route in &self.routes {
+            let captures = match route.matches(&parts) {
+                Some(captures) => captures,
+                None => continue,
+            };
+            match route.method {
+                Some(ref route_method)
+                        if *route_method != method
+                            && !(method == "HEAD" && route_method == "GET") => {
+                    if route_method == "GET" {
+                        allowed.insert("HEAD".to_owned());
+                    }
+                    allowed.insert(route_method.clone());
+                },
+                _ => {
+                    found = Some((route, captures));
+                    break;
+                },
+            }
+        }
+
+        match found {
+            Some((route, captures)) => {
+                debug!("routing {} {:?} with {:?}", method, path, captures);
+                request.path_params = captures;
+                route.handler.call(request)
+            },
+            None if allowed.is_empty() => {
+                debug!("no route for {:?}", path);
+                self.error_response(&request, 404, None)
+            },
+            None => {
+                debug!("no route for {} {:?}; allowed: {:?}", method, path, allowed);
+                let allow = allowed.into_iter().collect::<Vec<_>>().join(", ");
+                self.error_response(&request, 405, Some(allow))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::response::RequestState;
+
+    use futures::{Future, Stream};
+    use futures::future;
+    use futures::sync::mpsc;
+    use tokio_proto::streaming::Body;
+
+    use std::sync::Arc;
+
+    /// Responds with which route it is and the path params, one per line.
+    struct Named(&'static str);
+
+    impl FastcgiRequestHandler for Named {
+        fn call(&self, request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+            let mut params: Vec<_> = request.path_params.iter()
+                .map(|(name, value)| format!("{}={}\n", name, value))
+                .collect();
+            params.sort();
+            let out = format!("{}\n{}", self.0, params.concat());
+            let response = request.response();
+            Box::new(response.send_headers().and_then(move |mut body| {
+                body.buffer.extend_from_slice(out.as_bytes());
+                body.finish()
+            }))
+        }
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .route("get", "/users/:id", Named("user"))
+            .route("POST", "/users", Named("create"))
+            .route("GET", "/files/*path", Named("files"))
+            .any("/any/", Named("any"))
+    }
+
+    /// Route a request, returning the stdout of the response.
+    fn call(router: &Router, method: &str, path: &str) -> String {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut params = HashMap::new();
+        params.insert("REQUEST_METHOD".to_owned(), method.to_owned());
+        params.insert("REQUEST_URI".to_owned(), format!("{}?x=1", path));
+        let request = FastcgiRequest::new(Role::Responder, params, Body::empty(), 1, sender,
+                                          Arc::new(RequestState::default()));
+        future::lazy(|| router.call(request)).wait().unwrap();
+        let records = receiver.collect().wait().unwrap();
+        let mut out = vec![];
+        for record in records {
+            if let FastcgiRecordBody::Stdout(data) = record.body {
+                out.extend_from_slice(&data);
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn body(out: &str) -> &str {
+        &out[out.find("\r\n\r\n").unwrap() + 4 ..]
+    }
+
+    #[test]
+    fn params() {
+        let router = router();
+        assert_eq!(body(&call(&router, "GET", "/users/42")), "user\nid=42\n");
+        assert_eq!(body(&call(&router, "GET", "/users/42/")), "user\nid=42\n");
+        assert_eq!(body(&call(&router, "POST", "/users")), "create\n");
+        assert!(call(&router, "GET", "/users/42/x").starts_with("Status: 404 Not Found\r\n"));
+        assert!(call(&router, "GET", "/users").contains("Status: 405"));
+    }
+
+    #[test]
+    fn wildcards() {
+        let router = router();
+        assert_eq!(body(&call(&router, "GET", "/files/a/b/c.txt")), "files\npath=a/b/c.txt\n");
+        assert_eq!(body(&call(&router, "GET", "/files")), "files\npath=\n");
+        assert_eq!(body(&call(&router, "DELETE", "/any")), "any\n");
+        assert!(call(&router, "GET", "/other").starts_with("Status: 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let router = router();
+        let out = call(&router, "DELETE", "/users/42");
+        assert!(out.starts_with("Status: 405 Method Not Allowed\r\n"));
+        assert!(out.contains("\r\nAllow: GET, HEAD\r\n"));
+        let out = call(&router, "GET", "/users");
+        assert!(out.contains("\r\nAllow: POST\r\n"));
+    }
+
+    #[test]
+    fn head_is_get() {
+        let router = router();
+        assert_eq!(body(&call(&router, "HEAD", "/users/7")), "user\nid=7\n");
+        assert!(call(&router, "HEAD", "/users").contains("Status: 405"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn wildcard_not_last() {
+        Router::new().route("GET", "/*path/x", Named("bad"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Responses which have the web server send a file, using `X-Accel-Redirect` or `X-Sendfile`, so
+//! that the file's contents don't have to go through the FastCGI connection.
+
+use super::super::*;
+
+use futures::Future;
+
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub enum SendfileStyle {
+    /// For nginx: an `X-Accel-Redirect` header with the file's path under `root` appended to
+    /// `location`, which should be an `internal` location that maps to the root directory.
+    XAccelRedirect { location: String },
+
+    /// For Apache's mod_xsendfile and lighttpd: an `X-Sendfile` header with the file's full path.
+    XSendfile,
+}
+
+#[derive(Debug, Clone)]
+pub struct SendfileConfig {
+    /// The directory which files are sent from. Files outside of it are never sent.
+    pub root: PathBuf,
+    pub style: SendfileStyle,
+}
+
+impl SendfileConfig {
+    /// Find the file at the given path under the root directory. Leading slashes are ignored, so
+    /// this can be given a URL path. Returns the file's full path, and its path relative to the
+    /// root.
+    ///
+    /// This fails with `NotFound` if the file doesn't exist, isn't a regular file, or is outside of
+    /// the root directory, including by way of `..` or symlinks.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> io::Result<(PathBuf, PathBuf)> {
+        let relative: PathBuf = path.as_ref().components()
+            .filter(|c| !matches!(*c, Component::RootDir | Component::Prefix(_)))
+            .collect();
+
+        let root = fs::canonicalize(&self.root)?;
+        let full = fs::canonicalize(root.join(&relative))?;
+        let relative = match full.strip_prefix(&root) {
+            Ok(relative) => relative.to_owned(),
+            Err(_) => {
+                warn!("refusing to send {:?}: it is outside of {:?}", full, root);
+                return Err(not_found(path.as_ref()));
+            }
+        };
+        if !full.metadata()?.is_file() {
+            return Err(not_found(path.as_ref()));
+        }
+        Ok((full, relative))
+    }
+
+    /// The header to send for a file, as returned by `resolve`.
+    fn header(&self, full: &Path, relative: &Path) -> io::Result<(&'static str, String)> {
+        match self.style {
+            SendfileStyle::XAccelRedirect { ref location } => {
+                let mut uri = location.trim_end_matches('/').to_owned();
+                uri.push('/');
+                uri.push_str(&percent_encode(relative.as_os_str().as_bytes()));
+                Ok(("X-Accel-Redirect", uri))
+            },
+            SendfileStyle::XSendfile => {
+                match full.to_str() {
+                    Some(path) if !path.contains(['\r', '\n']) => {
+                        Ok(("X-Sendfile", path.to_owned()))
+                    },
+                    _ => {
+                        let msg = format!("can't put {:?} in an X-Sendfile header", full);
+                        error!("{}", msg);
+                        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                    },
+                }
+            }
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no file {:?} to send", path))
+}
+
+/// Percent-encode everything in a path except unreserved characters and slashes.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(b as char);
+            },
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+impl FastcgiHeadersResponse {
+    /// Have the web server send the file at the given path under the configured root directory as
+    /// the response body, and end the response. If the file can't be sent, a 404 response is sent
+    /// instead. Unless a `Content-Type` header was set, `application/octet-stream` is used.
+    pub fn send_file<P: AsRef<Path>>(mut self, path: P, config: &SendfileConfig)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+    {
+        let header = config.resolve(path.as_ref())
+            .and_then(|(full, relative)| config.header(&full, &relative));
+        match header {
+            Ok((name, value)) => {
+                debug!("sending file with {}: {}", name, value);
+                if self.get_header("Content-Type").is_none() {
+                    self.set_header("Content-Type", "application/octet-stream");
+                }
+                self.set_header(name, value);
+                Box::new(self.send_headers().and_then(|body| body.finish()))
+            },
+            Err(e) => {
+                info!("not sending file {:?}: {}", path.as_ref(), e);
+                self.set_status(404);
+                self.set_header("Content-Type", "text/plain");
+                Box::new(self.send_headers().and_then(|mut body| {
+                    body.buffer.extend_from_slice(b"404 Not Found\n");
+                    body.finish()
+                }))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::response::RequestState;
+
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use libc;
+
+    use std::env;
+    use std::ffi::CString;
+    use std::os::unix::fs::symlink;
+    use std::process;
+    use std::sync::Arc;
+
+    /// A directory of files to send, and a file outside of it, removed when dropped.
+    struct Files {
+        dir: PathBuf,
+        config: SendfileConfig,
+    }
+
+    impl Files {
+        fn new(name: &str, style: SendfileStyle) -> Files {
+            let dir = env::temp_dir().join(format!("tokio-fastcgi-{}-{}", name, process::id()));
+            let root = dir.join("root");
+            fs::create_dir_all(root.join("sub")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            fs::write(root.join("file.txt"), "file").unwrap();
+            fs::write(root.join("sub/a b.txt"), "a b").unwrap();
+            fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+            symlink("file.txt", root.join("inside")).unwrap();
+            symlink("../outside/secret.txt", root.join("outside")).unwrap();
+            let fifo = CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+            Files {
+                dir,
+                config: SendfileConfig { root, style },
+            }
+        }
+
+        /// A file's full path, without any symlinks.
+        fn path(&self, path: &str) -> PathBuf {
+            fs::canonicalize(self.dir.join(path)).unwrap()
+        }
+
+        /// The output of `send_file` for the path.
+        fn send(&self, path: &str) -> String {
+            let (sender, receiver) = mpsc::channel(16);
+            let state = Arc::new(RequestState::default());
+            FastcgiHeadersResponse::new(1, sender, state, WritePolicy::default())
+                .send_file(path, &self.config)
+                .wait()
+                .unwrap();
+            let out = receiver.collect().wait().unwrap().into_iter()
+                .flat_map(|record| match record.body {
+                    FastcgiRecordBody::Stdout(data) => data.to_vec(),
+                    body => panic!("unexpected record {:?}", body),
+                })
+                .collect();
+            String::from_utf8(out).unwrap()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let files = Files::new("resolve", SendfileStyle::XSendfile);
+        let resolved = |path| files.config.resolve(path).unwrap();
+        assert_eq!(resolved("/file.txt"), (files.path("root/file.txt"), "file.txt".into()));
+        assert_eq!(resolved("file.txt"), (files.path("root/file.txt"), "file.txt".into()));
+        assert_eq!(resolved("/sub/a b.txt"),
+                   (files.path("root/sub/a b.txt"), "sub/a b.txt".into()));
+        assert_eq!(resolved("/sub/../file.txt"), (files.path("root/file.txt"), "file.txt".into()));
+        // Symlinks within the root are followed.
+        assert_eq!(resolved("/inside"), (files.path("root/file.txt"), "file.txt".into()));
+    }
+
+    #[test]
+    fn resolve_not_found() {
+        let files = Files::new("not-found", SendfileStyle::XSendfile);
+        let not_found = [
+            "/missing.txt",
+            "/../outside/secret.txt",
+            "/sub/../../outside/secret.txt",
+            "/outside",
+            "/sub",
+            "/",
+            "/fifo",
+        ];
+        for path in &not_found {
+            let error = files.config.resolve(path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn x_accel_redirect() {
+        let style = SendfileStyle::XAccelRedirect { location: "/protected/".to_owned() };
+        let files = Files::new("x-accel-redirect", style);
+        let out = files.send("/sub/a b.txt");
+        assert!(out.contains("X-Accel-Redirect: /protected/sub/a%20b.txt\r\n"), "{:?}", out);
+        assert!(out.contains("Content-Type: application/octet-stream\r\n"), "{:?}", out);
+        assert!(!out.contains("Status:") && out.ends_with("\r\n\r\n"), "{:?}", out);
+    }
+
+    #[test]
+    fn x_sendfile() {
+        let files = Files::new("x-sendfile", SendfileStyle::XSendfile);
+        let out = files.send("/inside");
+        let header = format!("X-Sendfile: {}\r\n", files.path("root/file.txt").display());
+        assert!(out.contains(&header), "{:?}", out);
+    }
+
+    #[test]
+    fn send_file_not_found() {
+        let files = Files::new("send-not-found", SendfileStyle::XSendfile);
+        for path in &["/missing.txt", "/../outside/secret.txt", "/outside", "/sub"] {
+            let out = files.send(path);
+            assert!(out.starts_with("Status: 404 Not Found\r\n"), "{:?}", out);
+            assert!(out.ends_with("\r\n\r\n404 Not Found\n"), "{:?}", out);
+            assert!(!out.contains("X-Sendfile"), "{:?}", out);
+        }
+    }
+}
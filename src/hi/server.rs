@@ -0,0 +1,1002 @@
+//! A FastCGI server which listens on Unix and TCP sockets and serves each connection with a
+//! `FastcgiService`, so that programs don't have to do that themselves.
+
+use super::super::*;
+use super::allow_list::PeerAllowList;
+use super::listener::Listener;
+use super::prefork::supervise;
+use super::shutdown::{run_signal, RequestLimit, Shutdown};
+use super::systemd::{listen_fds, sd_notify};
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use futures::task::AtomicTask;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_uds::UnixListener;
+
+use std::fs::{self, Permissions};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc as std_mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for the connections to close after the shutdown deadline, once the requests
+/// left on them have been ended.
+const FORCED_END_WAIT: Duration = Duration::from_secs(1);
+
+/// An address for the server to listen on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindAddress {
+    /// A Unix socket at the given path. Any socket file already there is removed first.
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+    /// A listening socket, Unix or TCP, which this process was started with.
+    Fd(RawFd),
+    /// All the sockets systemd passed this process with socket activation.
+    Systemd,
+}
+
+impl From<SocketAddr> for BindAddress {
+    fn from(addr: SocketAddr) -> BindAddress {
+        BindAddress::Tcp(addr)
+    }
+}
+
+impl From<PathBuf> for BindAddress {
+    fn from(path: PathBuf) -> BindAddress {
+        BindAddress::Unix(path)
+    }
+}
+
+impl<'a> From<&'a Path> for BindAddress {
+    fn from(path: &'a Path) -> BindAddress {
+        BindAddress::Unix(path.to_owned())
+    }
+}
+
+/// Builds a `FastcgiServer`. Made with `FastcgiServer::builder`.
+pub struct FastcgiServerBuilder<H: FastcgiRequestHandler + 'static> {
+    handler: Arc<H>,
+    addresses: Vec<BindAddress>,
+    socket_mode: Option<u32>,
+    socket_owner: (Option<u32>, Option<u32>),
+    max_connections: Option<usize>,
+    allowed_peers: Option<PeerAllowList>,
+    threads: usize,
+    max_requests: Option<usize>,
+    shutdown_grace: Duration,
+    idle_timeout: Option<Duration>,
+    max_requests_per_connection: Option<usize>,
+    write_policy: WritePolicy,
+    error_page: Option<ErrorPage>,
+    timeouts: Timeouts,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    trace_context: bool,
+    handler_pool: Option<Arc<HandlerPool>>,
+    sd_notify: bool,
+}
+
+impl<H: FastcgiRequestHandler + 'static> FastcgiServerBuilder<H> {
+    /// Listen on a Unix socket at the given path.
+    pub fn bind_unix<P: Into<PathBuf>>(mut self, path: P) -> FastcgiServerBuilder<H> {
+        self.addresses.push(BindAddress::Unix(path.into()));
+        self
+    }
+
+    /// Listen on the given TCP address.
+    pub fn bind_tcp(mut self, addr: SocketAddr) -> FastcgiServerBuilder<H> {
+        self.addresses.push(BindAddress::Tcp(addr));
+        self
+    }
+
+    /// Accept connections on an inherited listening socket, Unix or TCP.
+    pub fn bind_fd(mut self, fd: RawFd) -> FastcgiServerBuilder<H> {
+        self.addresses.push(BindAddress::Fd(fd));
+        self
+    }
+
+    /// Accept connections on the listening socket a FastCGI process manager like spawn-fcgi or
+    /// mod_fcgid starts the process with, on `FCGI_LISTENSOCK_FILENO`. Check for it with
+    /// `is_listening_socket`.
+    pub fn bind_listensock(self) -> FastcgiServerBuilder<H> {
+        self.bind_fd(FCGI_LISTENSOCK_FILENO)
+    }
+
+    /// Accept connections on the sockets passed in by systemd, from a socket unit. Check for them
+    /// with `is_socket_activated`.
+    pub fn bind_systemd(mut self) -> FastcgiServerBuilder<H> {
+        self.addresses.push(BindAddress::Systemd);
+        self
+    }
+
+    /// Listen on the given address.
+    pub fn bind<A: Into<BindAddress>>(mut self, address: A) -> FastcgiServerBuilder<H> {
+        self.addresses.push(address.into());
+        self
+    }
+
+    /// Set the permissions of Unix sockets, like `0o660`. Otherwise they're left as the umask
+    /// makes them.
+    pub fn with_socket_mode(mut self, mode: u32) -> FastcgiServerBuilder<H> {
+        self.socket_mode = Some(mode);
+        self
+    }
+
+    /// Set the owning user and/or group of Unix sockets, by ID.
+    pub fn with_socket_owner(mut self, uid: Option<u32>, gid: Option<u32>)
+        -> FastcgiServerBuilder<H>
+    {
+        self.socket_owner = (uid, gid);
+        self
+    }
+
+    /// Limit how many connections are served at once. Connections beyond this are closed as soon
+    /// as they're accepted.
+    pub fn with_max_connections(mut self, max: usize) -> FastcgiServerBuilder<H> {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Only accept TCP connections from the given web servers. Otherwise, if
+    /// `FCGI_WEB_SERVER_ADDRS` is set, only those in it are accepted, as the FastCGI spec says.
+    pub fn with_allowed_peers(mut self, allowed_peers: PeerAllowList) -> FastcgiServerBuilder<H> {
+        self.allowed_peers = Some(allowed_peers);
+        self
+    }
+
+    /// Serve connections on this many threads in `run` and `run_prefork`, each with its own
+    /// reactor taking connections from all the listeners. The default is 1.
+    pub fn with_threads(mut self, threads: usize) -> FastcgiServerBuilder<H> {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Shut down gracefully after taking this many requests, as if the shutdown signal came. Use
+    /// this with `run_prefork`, to replace each worker after it has served its share of requests.
+    pub fn with_max_requests(mut self, max_requests: usize) -> FastcgiServerBuilder<H> {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Set how long to wait for the requests in flight to finish when shutting down, before ending
+    /// them. The default is 30 seconds.
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> FastcgiServerBuilder<H> {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Close connections the web server asked to keep open once they've had no requests in flight
+    /// for this long, so that one which leaks them can't run the server out of file descriptors.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> FastcgiServerBuilder<H> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Close connections the web server asked to keep open once they've served this many
+    /// requests, after the ones in flight finish. Requests begun on them meanwhile are answered
+    /// with `FCGI_OVERLOADED`.
+    pub fn with_max_requests_per_connection(mut self, max_requests: usize)
+        -> FastcgiServerBuilder<H>
+    {
+        self.max_requests_per_connection = Some(max_requests);
+        self
+    }
+
+    /// Set the default write policy for responses.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> FastcgiServerBuilder<H> {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set the page sent when a handler fails before sending any headers.
+    pub fn with_error_page(mut self, page: ErrorPage) -> FastcgiServerBuilder<H> {
+        self.error_page = Some(page);
+        self
+    }
+
+    /// Set the time limits for each phase of a request.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> FastcgiServerBuilder<H> {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Log each request to the given access log once it has completed.
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> FastcgiServerBuilder<H> {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Count connections, records and requests in the given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> FastcgiServerBuilder<H> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Pick up trace context from the `HTTP_TRACEPARENT` param of each request.
+    pub fn with_trace_context(mut self, enabled: bool) -> FastcgiServerBuilder<H> {
+        self.trace_context = enabled;
+        self
+    }
+
+    /// Call the handler and run its futures on the threads of the given pool, rather than on the
+    /// thread serving the request's connection, so that handlers which keep their thread busy
+    /// don't hold up the connections. The pool can be shared between servers.
+    pub fn with_handler_pool(mut self, pool: Arc<HandlerPool>) -> FastcgiServerBuilder<H> {
+        self.handler_pool = Some(pool);
+        self
+    }
+
+    /// Tell systemd when the server is ready, with `READY=1` once it's listening, and when it's
+    /// stopping, with `STOPPING=1` once it starts shutting down. This is for services with
+    /// `Type=notify`.
+    pub fn with_sd_notify(mut self, enabled: bool) -> FastcgiServerBuilder<H> {
+        self.sd_notify = enabled;
+        self
+    }
+
+    pub fn build(self) -> FastcgiServer<H> {
+        FastcgiServer {
+            addresses: self.addresses,
+            socket_mode: self.socket_mode,
+            socket_owner: self.socket_owner,
+            max_connections: self.max_connections,
+            allowed_peers: self.allowed_peers,
+            threads: self.threads,
+            request_limit: self.max_requests.map(|max| Arc::new(RequestLimit::new(max))),
+            connection_count: Arc::new(AtomicUsize::new(0)),
+            shutdown_grace: self.shutdown_grace,
+            sd_notify: self.sd_notify,
+            config: Arc::new(ServiceConfig {
+                handler: self.handler,
+                write_policy: self.write_policy,
+                error_page: self.error_page,
+                timeouts: self.timeouts,
+                idle_timeout: self.idle_timeout,
+                max_requests_per_connection: self.max_requests_per_connection,
+                access_log: self.access_log,
+                metrics: self.metrics,
+                trace_context: self.trace_context,
+                handler_pool: self.handler_pool,
+            }),
+        }
+    }
+}
+
+/// Everything needed to make the service for a connection.
+struct ServiceConfig<H: FastcgiRequestHandler + 'static> {
+    handler: Arc<H>,
+    write_policy: WritePolicy,
+    error_page: Option<ErrorPage>,
+    timeouts: Timeouts,
+    idle_timeout: Option<Duration>,
+    max_requests_per_connection: Option<usize>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    trace_context: bool,
+    handler_pool: Option<Arc<HandlerPool>>,
+}
+
+/// Serves FastCGI requests with a handler on one or more listening sockets.
+pub struct FastcgiServer<H: FastcgiRequestHandler + 'static> {
+    addresses: Vec<BindAddress>,
+    socket_mode: Option<u32>,
+    socket_owner: (Option<u32>, Option<u32>),
+    max_connections: Option<usize>,
+    allowed_peers: Option<PeerAllowList>,
+    threads: usize,
+    request_limit: Option<Arc<RequestLimit>>,
+    /// The number of connections open on all the threads.
+    connection_count: Arc<AtomicUsize>,
+    shutdown_grace: Duration,
+    sd_notify: bool,
+    config: Arc<ServiceConfig<H>>,
+}
+
+impl<H: FastcgiRequestHandler + 'static> FastcgiServer<H> {
+    pub fn builder(handler: H) -> FastcgiServerBuilder<H> {
+        FastcgiServerBuilder {
+            handler: Arc::new(handler),
+            addresses: vec![],
+            socket_mode: None,
+            socket_owner: (None, None),
+            max_connections: None,
+            allowed_peers: None,
+            threads: 1,
+            max_requests: None,
+            shutdown_grace: Duration::from_secs(30),
+            idle_timeout: None,
+            max_requests_per_connection: None,
+            write_policy: WritePolicy::default(),
+            error_page: None,
+            timeouts: Timeouts::default(),
+            access_log: None,
+            metrics: None,
+            trace_context: false,
+            handler_pool: None,
+            sd_notify: false,
+        }
+    }
+
+    /// Bind all the addresses, and return a future which accepts and serves connections on them
+    /// on the given reactor. Failures to accept a connection are logged, and the future never
+    /// completes.
+    pub fn serve(self, handle: &Handle) -> io::Result<Box<dyn Future<Item=(), Error=io::Error>>> {
+        self.serve_until(handle, future::empty())
+    }
+
+    /// Like `serve`, but shut down gracefully when the given future completes (or fails):
+    ///
+    /// * stop accepting connections;
+    /// * let the requests in flight finish, for up to the shutdown grace period, closing each
+    ///   connection once it has none left, even if the web server asked to keep it open;
+    /// * at the end of the grace period, end the requests left, as if they had timed out, but with
+    ///   a 503 status;
+    /// * remove the Unix socket files the server made.
+    ///
+    /// The returned future completes once all this is done.
+    pub fn serve_until<F>(self, handle: &Handle, signal: F)
+        -> io::Result<Box<dyn Future<Item=(), Error=io::Error>>>
+        where F: Future<Item=(), Error=()> + 'static
+    {
+        let bound = self.bind_all()?;
+        self.serve_on(handle, bound, signal, self.sd_notify)
+    }
+
+    /// Bind the server's addresses in the master process, and serve connections on them in
+    /// `workers` processes forked from it, as described in the `prefork` module. Each worker
+    /// serves on its own reactor like `run`, and is replaced when it exits, such as after
+    /// `with_max_requests` requests.
+    ///
+    /// This must be called before starting any threads or reactors. It returns once the master has
+    /// been told to shut down and all the workers have exited.
+    pub fn run_prefork(self, workers: usize) -> io::Result<()> {
+        let bound = self.bind_all()?;
+        let sd_notify = self.sd_notify;
+        if sd_notify {
+            notify("READY=1");
+        }
+        // The master removes the socket files once all the workers have exited.
+        let server = Arc::new(self);
+        let result = supervise(workers, || FastcgiServer::run_threads(&server, &bound, false));
+        if sd_notify {
+            notify("STOPPING=1");
+        }
+        for path in bound.iter().filter_map(|bound| bound.socket_path.as_ref()) {
+            remove_socket(path);
+        }
+        result
+    }
+
+    /// Bind all the addresses, or take over the inherited sockets.
+    fn bind_all(&self) -> io::Result<Vec<Bound>> {
+        if self.addresses.is_empty() {
+            let msg = "FastCGI server has no addresses to listen on";
+            error!("{}", msg);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let mut addresses = vec![];
+        for address in &self.addresses {
+            if let BindAddress::Systemd = *address {
+                let fds = listen_fds(true)?;
+                if fds.is_empty() {
+                    let msg = "systemd didn't pass any sockets to listen on";
+                    error!("{}", msg);
+                    return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+                }
+                for listen_fd in fds {
+                    debug!("systemd passed socket {:?} on fd {}", listen_fd.name, listen_fd.fd);
+                    addresses.push(BindAddress::Fd(listen_fd.fd));
+                }
+            } else {
+                addresses.push(address.clone());
+            }
+        }
+
+        let mut bound = vec![];
+        for address in &addresses {
+            let (listener, socket_path) = match *address {
+                BindAddress::Unix(ref path) => {
+                    let listener = self.bind_unix(path)?;
+                    info!("listening on {:?}", path);
+                    (Listener::Unix(listener), Some(path.clone()))
+                },
+                BindAddress::Tcp(ref addr) => {
+                    let listener = StdTcpListener::bind(addr).map_err(|e| {
+                        error!("failed to listen on {}: {}", addr, e);
+                        e
+                    })?;
+                    listener.set_nonblocking(true)?;
+                    info!("listening on {}", addr);
+                    (Listener::Tcp(listener), None)
+                },
+                BindAddress::Fd(fd) => {
+                    let listener = Listener::from_fd(fd)?;
+                    match listener {
+                        Listener::Unix(ref listener) => {
+                            info!("listening on inherited socket {:?}", listener.local_addr()?);
+                        },
+                        Listener::Tcp(ref listener) => {
+                            info!("listening on inherited socket {}", listener.local_addr()?);
+                        },
+                    }
+                    (listener, None)
+                },
+                BindAddress::Systemd => unreachable!("systemd sockets were already looked up"),
+            };
+            bound.push(Bound { listener, socket_path });
+        }
+        Ok(bound)
+    }
+
+    /// Serve connections on bound listeners until the signal, and then shut down as described in
+    /// `serve_until`.
+    fn serve_on<F>(&self, handle: &Handle, bound: Vec<Bound>, signal: F, sd_notify: bool)
+        -> io::Result<Box<dyn Future<Item=(), Error=io::Error>>>
+        where F: Future<Item=(), Error=()> + 'static
+    {
+        let allowed_peers = match self.allowed_peers {
+            Some(ref allowed_peers) => Some(allowed_peers.clone()),
+            None => PeerAllowList::from_env()?,
+        };
+        if let Some(ref allowed_peers) = allowed_peers {
+            info!("only accepting TCP connections from {}", allowed_peers);
+        }
+        let allowed_peers = allowed_peers.map(Arc::new);
+
+        let (mut trigger, mut shutdown) = Shutdown::channel();
+        let signal: Box<dyn Future<Item=(), Error=()>> = match self.request_limit {
+            Some(ref limit) => {
+                shutdown = shutdown.with_request_limit(limit.clone());
+                Box::new(signal.select(limit.reached()).map(|_| ()).map_err(|_| ()))
+            },
+            None => Box::new(signal),
+        };
+        let connections = Arc::new(Connections::default());
+        let mut socket_paths = vec![];
+        let mut listeners: Vec<Box<dyn Future<Item=(), Error=io::Error>>> = vec![];
+        for Bound { listener, socket_path } in bound {
+            let acceptor = Acceptor {
+                handle: handle.clone(),
+                config: self.config.clone(),
+                connections: connections.clone(),
+                connection_count: self.connection_count.clone(),
+                max_connections: self.max_connections,
+                allowed_peers: allowed_peers.clone(),
+                shutdown: shutdown.clone(),
+            };
+            let listener = match listener {
+                Listener::Unix(listener) => {
+                    let tokio_handle = handle.new_tokio_handle();
+                    acceptor.accept_unix(UnixListener::from_std(listener, tokio_handle)?)
+                },
+                Listener::Tcp(listener) => {
+                    let addr = listener.local_addr()?;
+                    acceptor.accept_tcp(TcpListener::from_listener(listener, &addr, handle)?)
+                },
+            };
+            socket_paths.extend(socket_path);
+            listeners.push(listener);
+        }
+
+        if sd_notify {
+            notify("READY=1");
+        }
+
+        // The listeners stop once draining starts.
+        let accepting = future::join_all(listeners).map(|_| ());
+
+        let grace = self.shutdown_grace;
+        let handle = handle.clone();
+        let stopping = signal
+            .then(move |_| {
+                info!("shutting down; waiting up to {:?} for {} connections to finish",
+                      grace, connections.count.load(Ordering::SeqCst));
+                if sd_notify {
+                    notify("STOPPING=1");
+                }
+                trigger.drain();
+                let deadline = Timeout::new(grace, &handle)?;
+                Ok::<_, io::Error>(Drained(connections).select2(deadline).then(move |result| {
+                    match result {
+                        Ok(Either::A(((), _deadline))) => Either::A(future::ok(())),
+                        Err(Either::A((e, _))) | Err(Either::B((e, _))) => {
+                            Either::A(future::err(e))
+                        },
+                        Ok(Either::B(((), drained))) => {
+                            warn!("shutdown grace period is up; ending the requests left");
+                            trigger.force();
+                            let wait = match Timeout::new(FORCED_END_WAIT, &handle) {
+                                Ok(wait) => wait,
+                                Err(e) => return Either::A(future::err(e)),
+                            };
+                            Either::B(drained.select(wait).map(|_| ()).map_err(|(e, _)| e))
+                        },
+                    }
+                }))
+            })
+            .flatten()
+            .then(move |result| {
+                for path in &socket_paths {
+                    remove_socket(path);
+                }
+                info!("shut down");
+                result
+            });
+
+        Ok(Box::new(accepting.join(stopping).map(|_| ())))
+    }
+
+    /// Serve connections on `with_threads` threads, each with its own reactor, until the process
+    /// gets `SIGTERM` or `SIGINT`, and then shut down gracefully. This thread waits for the signal.
+    /// Without the `signals` feature, this only stops at the `with_max_requests` limit.
+    pub fn run(self) -> io::Result<()> {
+        let bound = self.bind_all()?;
+        let sd_notify = self.sd_notify;
+        let result = FastcgiServer::run_threads(&Arc::new(self), &bound, sd_notify);
+        for path in bound.iter().filter_map(|bound| bound.socket_path.as_ref()) {
+            remove_socket(path);
+        }
+        result
+    }
+
+    /// Serve on `threads` threads, each with its own reactor and copies of the listeners, until
+    /// the process gets `SIGTERM` or `SIGINT`, or the request limit is reached. Then shut them
+    /// all down gracefully. If a thread fails to start serving, or stops early, the others are
+    /// shut down too, and its error returned. This leaves removing the socket files to the caller.
+    fn run_threads(server: &Arc<FastcgiServer<H>>, bound: &[Bound], sd_notify: bool)
+        -> io::Result<()>
+    {
+        let mut stops = vec![];
+        let mut threads = vec![];
+        let (started, starts) = std_mpsc::channel::<io::Result<()>>();
+        let (exited, exits) = mpsc::unbounded::<()>();
+        for i in 0 .. server.threads {
+            let listeners = bound.iter()
+                .map(|bound| Ok(Bound { listener: bound.listener.try_clone()?, socket_path: None }))
+                .collect::<io::Result<Vec<Bound>>>()?;
+            let (stop, stopped) = oneshot::channel::<()>();
+            let server = server.clone();
+            let started = started.clone();
+            let exited = ThreadExit(exited.clone());
+            let thread = thread::Builder::new()
+                .name(format!("fastcgi-{}", i))
+                .spawn(move || {
+                    let _exited = exited;
+                    let serving = Core::new().and_then(|core| {
+                        let signal = stopped.then(|_| Ok(()));
+                        let serving = server.serve_on(&core.handle(), listeners, signal, false)?;
+                        Ok((core, serving))
+                    });
+                    match serving {
+                        Ok((mut core, serving)) => {
+                            let _ = started.send(Ok(()));
+                            drop(started);
+                            core.run(serving)
+                        },
+                        // The error is returned from `run_threads` instead.
+                        Err(e) => {
+                            let _ = started.send(Err(e));
+                            Ok(())
+                        },
+                    }
+                })?;
+            stops.push(stop);
+            threads.push(thread);
+        }
+        drop(started);
+
+        // Wait for all the threads to start serving, or for one to fail to.
+        let mut failed = None;
+        let mut serving = 0;
+        while serving < threads.len() {
+            match starts.recv() {
+                Ok(Ok(())) => serving += 1,
+                Ok(Err(e)) => {
+                    failed = Some(e);
+                    break;
+                },
+                // A thread panicked, which joining it reports.
+                Err(_) => break,
+            }
+        }
+
+        if serving == threads.len() {
+            if sd_notify {
+                notify("READY=1");
+            }
+            let mut core = Core::new()?;
+            let limit_reached = match server.request_limit {
+                Some(ref limit) => limit.reached(),
+                None => Box::new(future::empty()),
+            };
+            let thread_exited = exits.into_future().then(|_| {
+                warn!("a server thread stopped early; shutting down the others");
+                Ok::<(), ()>(())
+            });
+            let _ = core.run(run_signal().select(limit_reached).select2(thread_exited));
+            if sd_notify {
+                notify("STOPPING=1");
+            }
+        }
+        for stop in stops {
+            let _ = stop.send(());
+        }
+        let result = threads.into_iter()
+            .map(|thread| thread.join().unwrap_or_else(|_| {
+                let msg = "a server thread panicked";
+                error!("{}", msg);
+                Err(io::Error::new(io::ErrorKind::Other, msg))
+            }))
+            .fold(Ok(()), Result::and);
+        match failed {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    fn bind_unix(&self, path: &Path) -> io::Result<StdUnixListener> {
+        match fs::symlink_metadata(path) {
+            Ok(ref metadata) if metadata.file_type().is_socket() => {
+                debug!("removing old socket {:?}", path);
+                fs::remove_file(path)?;
+            },
+            Ok(_) => {
+                let msg = format!("{:?} exists and is not a socket", path);
+                error!("{}", msg);
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let listener = StdUnixListener::bind(path).map_err(|e| {
+            error!("failed to listen on {:?}: {}", path, e);
+            e
+        })?;
+        listener.set_nonblocking(true)?;
+        if let Some(mode) = self.socket_mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        if self.socket_owner != (None, None) {
+            chown(path, self.socket_owner.0, self.socket_owner.1)?;
+        }
+        Ok(listener)
+    }
+}
+
+fn remove_socket(path: &Path) {
+    debug!("removing socket {:?}", path);
+    if let Err(e) = fs::remove_file(path) {
+        warn!("failed to remove socket {:?}: {}", path, e);
+    }
+}
+
+fn notify(state: &str) {
+    if let Err(e) = sd_notify(state) {
+        warn!("failed to send {:?} to systemd: {}", state, e);
+    }
+}
+
+/// Tells the thread running the server when one of the threads serving connections exits, however
+/// it does.
+struct ThreadExit(mpsc::UnboundedSender<()>);
+
+impl Drop for ThreadExit {
+    fn drop(&mut self) {
+        let _ = self.0.unbounded_send(());
+    }
+}
+
+/// A listener the server bound or took over, and the socket file to remove when it shuts down, if
+/// it made one.
+struct Bound {
+    listener: Listener,
+    socket_path: Option<PathBuf>,
+}
+
+/// Serves the connections accepted from one listener.
+struct Acceptor<H: FastcgiRequestHandler + 'static> {
+    handle: Handle,
+    config: Arc<ServiceConfig<H>>,
+    connections: Arc<Connections>,
+    connection_count: Arc<AtomicUsize>,
+    max_connections: Option<usize>,
+    allowed_peers: Option<Arc<PeerAllowList>>,
+    shutdown: Shutdown,
+}
+
+impl<H: FastcgiRequestHandler + 'static> Acceptor<H> {
+    /// Accept connections until draining starts.
+    fn until_draining<F>(shutdown: &Shutdown, accepting: F)
+        -> Box<dyn Future<Item=(), Error=io::Error>>
+        where F: Future<Item=(), Error=io::Error> + 'static
+    {
+        let draining = shutdown.draining().then(|_| Ok(()));
+        Box::new(accepting.select(draining).map(|_| ()).map_err(|(e, _)| e))
+    }
+
+    fn accept_unix(self, listener: UnixListener) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let shutdown = self.shutdown.clone();
+        Acceptor::<H>::until_draining(&shutdown, listener.incoming()
+            .then(Ok::<_, io::Error>)
+            .for_each(move |result| {
+                match result {
+                    Ok(socket) => self.serve(socket),
+                    Err(e) => error!("failed to accept a connection: {}", e),
+                }
+                Ok(())
+            }))
+    }
+
+    fn accept_tcp(self, listener: TcpListener) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let shutdown = self.shutdown.clone();
+        Acceptor::<H>::until_draining(&shutdown, listener.incoming()
+            .then(Ok::<_, io::Error>)
+            .for_each(move |result| {
+                match result {
+                    Ok((socket, peer)) => {
+                        debug!("connection from {}", peer);
+                        if let Some(ref allowed_peers) = self.allowed_peers {
+                            if !allowed_peers.allows(peer.ip()) {
+                                warn!("rejecting connection from {}, which isn't an allowed \
+                                       web server", peer);
+                                return Ok(());
+                            }
+                        }
+                        if let Err(e) = socket.set_nodelay(true) {
+                            warn!("failed to set TCP_NODELAY: {}", e);
+                        }
+                        self.serve(socket);
+                    },
+                    Err(e) => error!("failed to accept a connection: {}", e),
+                }
+                Ok(())
+            }))
+    }
+
+    fn serve<IO: AsyncRead + AsyncWrite + 'static>(&self, io: IO) {
+        self.connections.count.fetch_add(1, Ordering::SeqCst);
+        let open = self.connection_count.fetch_add(1, Ordering::SeqCst);
+        let io = Counted {
+            io,
+            connections: self.connections.clone(),
+            connection_count: self.connection_count.clone(),
+        };
+        if let Some(max) = self.max_connections {
+            if open >= max {
+                warn!("already serving {} connections; closing a new one", open);
+                return;
+            }
+        }
+
+        let config = &self.config;
+        let mut service = FastcgiService::new(self.handle.remote().clone(), config.handler.clone())
+            .with_write_policy(config.write_policy)
+            .with_timeouts(config.timeouts)
+            .with_trace_context(config.trace_context)
+            .with_shutdown(self.shutdown.clone());
+        let mut proto = FastcgiProto::new()
+            .with_shutdown(self.shutdown.clone());
+        if let Some(timeout) = config.idle_timeout {
+            proto = proto.with_idle_timeout(timeout, self.handle.clone());
+        }
+        if let Some(max_requests) = config.max_requests_per_connection {
+            proto = proto.with_max_requests_per_connection(max_requests);
+        }
+        if let Some(ref page) = config.error_page {
+            service = service.with_error_page(page.clone());
+        }
+        if let Some(ref access_log) = config.access_log {
+            service = service.with_access_log(access_log.clone());
+        }
+        if let Some(ref metrics) = config.metrics {
+            service = service.with_metrics(metrics.clone());
+            proto = proto.with_metrics(metrics.clone());
+        }
+        if let Some(ref pool) = config.handler_pool {
+            service = service.with_handler_pool(pool.clone());
+        }
+        proto.bind_service(&self.handle, io, service);
+    }
+}
+
+/// The number of connections being served on one reactor, and the task waiting for there to be
+/// none left.
+#[derive(Default)]
+struct Connections {
+    count: AtomicUsize,
+    drained: AtomicTask,
+}
+
+/// Completes once there are no connections left.
+struct Drained(Arc<Connections>);
+
+impl Future for Drained {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.0.drained.register();
+        if self.0.count.load(Ordering::SeqCst) == 0 {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A connection, which counts as open until it's dropped.
+struct Counted<IO> {
+    io: IO,
+    connections: Arc<Connections>,
+    connection_count: Arc<AtomicUsize>,
+}
+
+impl<IO> Drop for Counted<IO> {
+    fn drop(&mut self) {
+        self.connection_count.fetch_sub(1, Ordering::SeqCst);
+        self.connections.count.fetch_sub(1, Ordering::SeqCst);
+        self.connections.drained.notify();
+    }
+}
+
+impl<IO: Read> Read for Counted<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<IO: Write> Write for Counted<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<IO: AsyncRead> AsyncRead for Counted<IO> {}
+
+impl<IO: AsyncWrite> AsyncWrite for Counted<IO> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    use std::env;
+    use std::os::unix::net::UnixStream as StdUnixStream;
+    use std::process;
+    use std::thread::JoinHandle;
+
+    type Handler = fn(FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>>;
+
+    fn ok(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        Box::new(request.response().send_headers().and_then(|mut body| {
+            body.buffer.extend_from_slice(b"ok");
+            body.finish()
+        }))
+    }
+
+    /// A socket path for a test, removed first in case an earlier run left it.
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("tokio-fastcgi-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Serve on a thread until told to stop, once the server has bound its addresses.
+    fn serve(server: FastcgiServer<Handler>) -> (oneshot::Sender<()>, JoinHandle<io::Result<()>>)
+    {
+        let (stop, stopped) = oneshot::channel();
+        let (bound, binding) = std_mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut core = Core::new()?;
+            let serving = server.serve_until(&core.handle(), stopped.then(|_| Ok(())));
+            let serving = match serving {
+                Ok(serving) => serving,
+                Err(e) => {
+                    let _ = bound.send(());
+                    return Err(e);
+                },
+            };
+            let _ = bound.send(());
+            core.run(serving)
+        });
+        binding.recv().unwrap();
+        (stop, thread)
+    }
+
+    fn connect(path: &Path) -> StdUnixStream {
+        let client = StdUnixStream::connect(path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+    }
+
+    fn single_request() -> Vec<u8> {
+        let mut out = begin_request(1, false);
+        out.extend(params(1, &[]));
+        out.extend(record(STDIN, 1, b""));
+        out
+    }
+
+    #[test]
+    fn unix_socket() {
+        let path = socket_path("unix-socket");
+        let server = FastcgiServer::builder(ok as Handler)
+            .bind_unix(&path)
+            .with_socket_mode(0o660)
+            .build();
+        let (stop, thread) = serve(server);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+        let mut client = connect(&path);
+        client.write_all(&single_request()).unwrap();
+        let out = read_until_closed(&mut client);
+        assert!(stream(&out, STDOUT, 1).ends_with("\r\n\r\nok"));
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+
+        stop.send(()).unwrap();
+        thread.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn max_connections() {
+        let path = socket_path("max-connections");
+        let server = FastcgiServer::builder(ok as Handler)
+            .bind_unix(&path)
+            .with_max_connections(1)
+            .build();
+        let (stop, thread) = serve(server);
+
+        // Once the first connection has been served, there's no room for a second.
+        let mut first = connect(&path);
+        first.write_all(&request(1)).unwrap();
+        read_until_ended(&mut first, 1);
+        let mut second = connect(&path);
+        assert!(read_until_closed(&mut second).is_empty());
+
+        // The first can still make requests, and once it's closed there's room again, as soon as
+        // the server notices.
+        first.write_all(&request(2)).unwrap();
+        assert_eq!(end_records(&read_until_ended(&mut first, 1)), vec![(2, 0)]);
+        drop(first);
+        let served = (0 .. 50).any(|_| {
+            thread::sleep(Duration::from_millis(20));
+            let mut third = connect(&path);
+            third.write_all(&single_request()).is_ok()
+                && end_records(&read_until_closed(&mut third)) == vec![(1, 0)]
+        });
+        assert!(served);
+
+        stop.send(()).unwrap();
+        thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn bind_fails() {
+        let path = socket_path("bind-fails").join("missing.sock");
+        let server = FastcgiServer::builder(ok as Handler)
+            .bind_unix(&path)
+            .build();
+        assert!(server.run().is_err());
+    }
+}
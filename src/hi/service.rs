@@ -1,19 +1,38 @@
 use super::super::*;
+use super::access_log::AccessLogEntry;
+use super::response::{chunk_records, RequestState};
+use super::shutdown::{shutdown_status, with_deadline};
+use super::timeout::{timeout_status, with_timeout, BodyTimeout, Phase};
+use super::trace::{request_span, Instrument};
 
 use bytes::BytesMut;
-use futures::{future, stream, Future, Sink, Stream};
-use futures::sync::mpsc;
+use futures::{future, stream, Async, Future, Poll, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
 use tokio_core::reactor::Remote;
 use tokio_proto::streaming::{Message, Body};
 use tokio_service::Service;
 
+use std::any::Any;
 use std::collections::HashMap;
 use std::io;
-use std::sync::Arc;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 pub struct FastcgiService<H: FastcgiRequestHandler + 'static> {
     reactor_handle: Remote,
     handler: Arc<H>,
+    write_policy: WritePolicy,
+    error_page: Arc<ErrorPage>,
+    timeouts: Timeouts,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    span: Option<Span>,
+    request_spans: Option<RequestSpans>,
+    trace_context: bool,
+    shutdown: Option<Shutdown>,
+    handler_pool: Option<Arc<HandlerPool>>,
 }
 
 impl<H: FastcgiRequestHandler + 'static> FastcgiService<H> {
@@ -21,14 +40,223 @@ impl<H: FastcgiRequestHandler + 'static> FastcgiService<H> {
         FastcgiService {
             reactor_handle,
             handler,
+            write_policy: WritePolicy::default(),
+            error_page: Arc::new(ErrorPage::default()),
+            timeouts: Timeouts::default(),
+            access_log: None,
+            metrics: None,
+            span: None,
+            request_spans: None,
+            trace_context: false,
+            shutdown: None,
+            handler_pool: None,
         }
     }
+
+    /// Set the default write policy for responses.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> FastcgiService<H> {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set the page sent when a handler fails before sending any headers.
+    pub fn with_error_page(mut self, page: ErrorPage) -> FastcgiService<H> {
+        self.error_page = Arc::new(page);
+        self
+    }
+
+    /// Set the time limits for each phase of a request. When one runs out, the request gets a 503
+    /// (for reading the request) or 504 (for the handler) response, or if the handler already sent
+    /// headers, an error on stderr and a non-zero app status.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> FastcgiService<H> {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Log each request to the given access log once it has completed.
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> FastcgiService<H> {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Count requests and time handlers in the given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> FastcgiService<H> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Make the span of each request inside the given connection span. `FastcgiProto::bind_service`
+    /// does this with the connection's span. Otherwise they're inside whatever span is current.
+    pub fn with_span(mut self, span: Span) -> FastcgiService<H> {
+        self.span = Some(span);
+        self
+    }
+
+    /// Run each request in its span from the transport's `RequestSpans`, rather than making one.
+    /// `FastcgiProto::bind_service` does this with the connection's `RequestSpans`.
+    pub fn with_request_spans(mut self, request_spans: RequestSpans) -> FastcgiService<H> {
+        self.request_spans = Some(request_spans);
+        self
+    }
+
+    /// End requests which are still in flight at the shutdown deadline, with a 503 response if
+    /// they haven't sent headers yet, or an error on stderr and a non-zero app status otherwise.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> FastcgiService<H> {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Pick up trace context from the `HTTP_TRACEPARENT` param: record its trace and parent IDs in
+    /// the request's span, and give it to the handler as `FastcgiRequest::trace_parent`.
+    pub fn with_trace_context(mut self, enabled: bool) -> FastcgiService<H> {
+        self.trace_context = enabled;
+        self
+    }
+
+    /// Call the handler and run its futures on the given pool's threads, rather than on this
+    /// service's reactor.
+    pub fn with_handler_pool(mut self, pool: Arc<HandlerPool>) -> FastcgiService<H> {
+        self.handler_pool = Some(pool);
+        self
+    }
 }
 
 fn invalid_data<T: Into<String>>(msg: T) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, msg.into())
 }
 
+fn end_records(request_id: u16, app_status: u32) -> Vec<FastcgiRecord> {
+    vec![
+        FastcgiRecord {
+            request_id,
+            body: FastcgiRecordBody::Stdout(BytesMut::new()),
+        },
+        FastcgiRecord {
+            request_id,
+            body: FastcgiRecordBody::Stderr(BytesMut::new()),
+        },
+        FastcgiRecord {
+            request_id,
+            body: FastcgiRecordBody::EndRequest(EndRequest {
+                app_status,
+                protocol_status: ProtocolStatus::RequestComplete,
+            })
+        },
+    ]
+}
+
+fn panic_error(id: u16, payload: Box<dyn Any + Send>) -> io::Error {
+    let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "(no message)".to_owned());
+    error!("request {}: handler panicked: {}", id, msg);
+    io::Error::new(io::ErrorKind::Other, format!("handler panicked: {}", msg))
+}
+
+/// Call the handler, turning any panic from calling it or polling its future into an error, so
+/// that it only affects this request.
+fn call_handler<H: FastcgiRequestHandler>(id: u16, handler: &H, request: FastcgiRequest)
+    -> Box<dyn Future<Item=(), Error=io::Error>>
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| handler.call(request))) {
+        Ok(future) => {
+            Box::new(AssertUnwindSafe(future)
+                .catch_unwind()
+                .then(move |result| result.unwrap_or_else(|payload| Err(panic_error(id, payload)))))
+        },
+        Err(payload) => Box::new(future::err(panic_error(id, payload))),
+    }
+}
+
+/// Report a failed request to the web server: send an error page if the handler didn't get as far
+/// as sending headers, write the error to stderr, and set a non-zero app status. This only fails if
+/// the records can't be sent.
+fn send_error(
+    request_id: u16,
+    sender: mpsc::Sender<FastcgiRecord>,
+    state: &Arc<RequestState>,
+    error_page: &ErrorPage,
+    status: u16,
+    error: &io::Error,
+    ) -> Box<dyn Future<Item=(), Error=io::Error>>
+{
+    // A non-zero app status means the error has already been reported on stderr.
+    let mut records = vec![];
+    if state.app_status() == 0 {
+        state.set_app_status(1);
+        let msg = format!("request {} failed: {}\n", request_id, error);
+        records = chunk_records(request_id, msg.as_bytes(), FastcgiRecordBody::Stderr);
+    }
+    let page = if state.headers_sent() {
+        Box::new(future::ok(()))
+    } else {
+        let response = FastcgiHeadersResponse::new(
+            request_id, sender.clone(), state.clone(), WritePolicy::default());
+        error_page.send(response, status)
+    };
+    Box::new(page.and_then(move |()| {
+        sender.send_all(stream::iter_ok(records))
+            .map(|_| ())
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }))
+}
+
+/// The records sent by a request's response objects. Once the request is cancelled, the channel is
+/// closed, so that no more records can be sent, and this ends after the ones already sent.
+struct ResponseRecords {
+    receiver: mpsc::Receiver<FastcgiRecord>,
+    cancel: Option<oneshot::Receiver<()>>,
+}
+
+impl ResponseRecords {
+    fn new(receiver: mpsc::Receiver<FastcgiRecord>, cancel: oneshot::Receiver<()>)
+        -> ResponseRecords
+    {
+        ResponseRecords {
+            receiver,
+            cancel: Some(cancel),
+        }
+    }
+}
+
+impl Stream for ResponseRecords {
+    type Item = FastcgiRecord;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<FastcgiRecord>, ()> {
+        let cancelled = match self.cancel.as_mut().map(|cancel| cancel.poll()) {
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Ok(Async::NotReady)) | None => false,
+            // The request finished without being cancelled.
+            Some(Err(oneshot::Canceled)) => {
+                self.cancel = None;
+                false
+            },
+        };
+        if cancelled {
+            debug!("request cancelled; closing its response channel");
+            self.receiver.close();
+            self.cancel = None;
+        }
+        self.receiver.poll()
+    }
+}
+
+/// Finish the access log entry for a request with how the response went, and log it.
+fn log_access(
+    access_log: &AccessLog,
+    entry: &Mutex<AccessLogEntry>,
+    state: &RequestState,
+    started: Instant,
+    )
+{
+    let mut entry = entry.lock().unwrap().clone();
+    entry.status = state.status();
+    entry.bytes = state.body_bytes();
+    entry.duration = started.elapsed();
+    access_log.log(&entry);
+}
+
 impl<H: FastcgiRequestHandler + 'static> Service for FastcgiService<H> {
     type Request = Message<FastcgiRecord, Body<FastcgiRecord, io::Error>>;
     type Response = Message<FastcgiRecord, Body<FastcgiRecord, io::Error>>;
@@ -36,6 +264,14 @@ impl<H: FastcgiRequestHandler + 'static> Service for FastcgiService<H> {
     type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
+        let handle = match self.reactor_handle.handle() {
+            Some(handle) => handle,
+            None => {
+                let msg = "FastcgiService called from outside of its reactor's thread";
+                error!("{}", msg);
+                return Box::new(future::err(io::Error::new(io::ErrorKind::Other, msg)));
+            }
+        };
 
         // Read records from the body stream until the empty Params record, building up the params
         // hash map.
@@ -66,6 +302,24 @@ impl<H: FastcgiRequestHandler + 'static> Service for FastcgiService<H> {
             },
         };
 
+        if let Some(ref shutdown) = self.shutdown {
+            shutdown.request_started();
+        }
+
+        let span = self.request_spans.as_ref()
+            .and_then(|request_spans| request_spans.get(id))
+            .unwrap_or_else(|| request_span(self.span.as_ref(), id, begin_request.role));
+        let params_span = span.clone();
+        let trace_context = self.trace_context;
+        let started = Instant::now();
+        let metrics = self.metrics.clone();
+        let handler_metrics = self.metrics.clone();
+        let in_flight = self.metrics.as_ref().map(|metrics| metrics.request_started());
+        let role = begin_request.role;
+        let access_log = self.access_log.clone();
+        let log_entry = Arc::new(Mutex::new(AccessLogEntry::new(id)));
+        let params_log_entry = log_entry.clone();
+
         let params_map = HashMap::<String, String>::new();
 
         let stream_process = StreamProcess::new(
@@ -98,10 +352,18 @@ impl<H: FastcgiRequestHandler + 'static> Service for FastcgiService<H> {
             }
         );
 
-        let reactor_handle = self.reactor_handle.clone();
         let (response_sender, response_receiver) = mpsc::channel::<FastcgiRecord>(1);
+        let (cancel_sender, cancel_receiver) = oneshot::channel();
+        let state = Arc::new(RequestState::new(self.reactor_handle.clone()));
+        let request_state = state.clone();
+        let error_state = state.clone();
+        let error_sender = response_sender.clone();
 
-        let request_future = stream_process.and_then(move |(body_record_stream, params)| {
+        let timeouts = self.timeouts;
+        let body_handle = handle.clone();
+        let write_policy = self.write_policy;
+        let params_future = with_timeout(stream_process, Phase::Params, timeouts.params, &handle);
+        let request_future = params_future.and_then(move |(body_record_stream, params)| {
             macro_rules! param {
                 ($name:expr) => {
                     params.get($name)
@@ -111,126 +373,340 @@ impl<H: FastcgiRequestHandler + 'static> Service for FastcgiService<H> {
             }
 
             info!("remote {:?} -> request for {:?}", param!("REMOTE_ADDR"), param!("REQUEST_URI"));
+            params_log_entry.lock().unwrap().set_params(&params);
+            if let Some(uri) = params.get("REQUEST_URI") {
+                params_span.record("uri", uri.as_str());
+            }
+            let trace_parent = if trace_context {
+                params.get("HTTP_TRACEPARENT").and_then(|value| {
+                    let trace_parent = TraceParent::parse(value);
+                    if trace_parent.is_none() {
+                        debug!("ignoring invalid traceparent {:?}", value);
+                    }
+                    trace_parent
+                })
+            } else {
+                None
+            };
+            if let Some(ref trace_parent) = trace_parent {
+                params_span.record("trace_id", trace_parent.trace_id.as_str());
+                params_span.record("parent_id", trace_parent.parent_id.as_str());
+            }
 
-            Ok(FastcgiRequest::new(
+            let mut request = FastcgiRequest::new(
                 begin_request.role,
                 params,
                 body_record_stream,
                 id,
                 response_sender,
-            ))
+                request_state,
+            );
+            request.set_write_policy(write_policy);
+            request.trace_parent = trace_parent;
+            if let Some(duration) = timeouts.body {
+                let body = mem::replace(&mut request.body, Box::new(stream::empty()));
+                request.body = Box::new(BodyTimeout::new(body, duration, &body_handle)?);
+            }
+            Ok(request)
         });
 
         let handler = self.handler.clone();
-        let response_future = request_future
+        let error_page = self.error_page.clone();
+        let handler_handle = handle.clone();
+        let handler_pool = self.handler_pool.clone();
+        let handler_span = span.clone();
+
+        let handled = request_future
             .and_then(move |request| {
-                // We need to start the handler and run it to completion.
-                // Meanwhile, we need to get the first record from `response_receiver` and
-                //  return it as a `Message` with a body,
-                //  and continue to pump records out and send them to the body.
-
-                // This makes a stream that yields nothing and finishes only once the handler is
-                // done. It allows us to drive the handler while simultaneously pumping messages,
-                // by merging the two streams together.
-                let handler_stream: Box<dyn Stream<Item = Option<FastcgiRecord>, Error = io::Error>>
-                    = Box::new(
-                        handler.call(request)
-                            .into_stream()
-                            .filter(|&()| {
-                                debug!("handler completed");
-                                 false
+                debug!("calling handler");
+                let called = Instant::now();
+                let future = match handler_pool {
+                    Some(pool) => pool.spawn_fn(move || {
+                        call_handler(id, &*handler, request).instrument(handler_span)
+                    }),
+                    None => call_handler(id, &*handler, request),
+                };
+                with_timeout(future, Phase::Handler, timeouts.handler, &handler_handle)
+                    .then(move |result| {
+                        debug!("handler finished after {:?}; ok: {}", called.elapsed(),
+                               result.is_ok());
+                        if let Some(metrics) = handler_metrics {
+                            metrics.handler_finished(called.elapsed());
+                        }
+                        result
+                    })
+            });
+        let handled = match self.shutdown {
+            Some(ref shutdown) => with_deadline(handled, shutdown),
+            None => Box::new(handled),
+        };
+
+        // This makes a stream that yields nothing and finishes only once the handler is done. It
+        // allows us to drive the handler while simultaneously pumping messages, by merging the two
+        // streams together.
+        let handler_stream: Box<dyn Stream<Item = Option<FastcgiRecord>, Error = io::Error>>
+            = Box::new(
+                handled
+                    .or_else(move |e| {
+                        // Keep the error to this request, rather than failing the connection, and
+                        // all the other requests on it along with it.
+                        error!("request {} failed: {}", id, e);
+                        let status = timeout_status(&e)
+                            .or_else(|| shutdown_status(&e))
+                            .unwrap_or(500);
+                        send_error(id, error_sender, &error_state, &error_page, status, &e)
+                            .map(move |()| {
+                                // Nothing more goes out for this request once the error has
+                                // been reported, even if the handler left something running.
+                                let _ = cancel_sender.send(());
                             })
-                            .map(|_| None) // never called; just for changing the type.
-                    );
-
-                // We also have `response_receiver`, which is a stream of `FastcgiRecord`,
-                // which are the records the handler generates as it runs.
-                let record_stream: Box<dyn Stream<Item = Option<FastcgiRecord>, Error = io::Error>>
-                    = Box::new(
-                        response_receiver.map(Some)
-                            .or_else(|()| Ok(None))
-                    );
-
-                // Take the first record received.
-                handler_stream.select(record_stream)
-                    .into_future()
-                    .map_err(|(e, _stream)| e)
-                    .map(move |(maybe_record, record_stream)| {
-                        debug!("merged streams yielded something: {:?}", maybe_record);
-
-                        let end_records = vec![
-                            FastcgiRecord {
-                                request_id: id,
-                                body: FastcgiRecordBody::Stdout(BytesMut::new()),
-                            },
-                            FastcgiRecord {
-                                request_id: id,
-                                body: FastcgiRecordBody::Stderr(BytesMut::new()),
-                            },
+                    })
+                    .into_stream()
+                    .filter(|&()| {
+                        debug!("handler completed");
+                         false
+                    })
+                    .map(|_| None) // never called; just for changing the type.
+            );
+
+        // We also have `response_receiver`, which is a stream of `FastcgiRecord`, which are the
+        // records the handler generates as it runs.
+        let record_stream: Box<dyn Stream<Item = Option<FastcgiRecord>, Error = io::Error>>
+            = Box::new(
+                ResponseRecords::new(response_receiver, cancel_receiver)
+                    .map(Some)
+                    .or_else(|()| Ok(None))
+            );
+
+        // We need to run the handler to completion. Meanwhile, we need to get the first record
+        // from `response_receiver` and return it as a `Message` with a body, and continue to pump
+        // records out and send them to the body.
+
+        // Take the first record received.
+        let body_span = span.clone();
+        let response_future = handler_stream.select(record_stream)
+            .into_future()
+            .map_err(|(e, _stream)| e)
+            .map(move |(maybe_record, record_stream)| {
+                debug!("merged streams yielded something: {:?}", maybe_record);
+
+                // The app status isn't known until the handler is done, so don't make the end
+                // records until the response records are all through.
+                let end_records = future::lazy(move || {
+                    if let Some(access_log) = access_log {
+                        log_access(&access_log, &log_entry, &state, started);
+                    }
+                    if let Some(metrics) = metrics {
+                        metrics.request_finished(role, state.status());
+                    }
+                    drop(in_flight);
+                    debug!("ending request with app status {}", state.app_status());
+                    Ok(stream::iter_ok(end_records(id, state.app_status())))
+                }).flatten_stream();
+
+                match maybe_record {
+                    Some(Some(first_record)) => {
+                        debug!("first record received");
+
+                        let records = record_stream
+                            .map(|maybe_record| maybe_record.unwrap())
+                            .chain(end_records)
+                            .then(Ok);
+
+                        let (body_sender, body) = Body::<FastcgiRecord, io::Error>::pair();
+
+                        // Schedule a future to pump remaining records through to the body.
+                        handle.spawn(
+                            body_sender.send_all(records)
+                                .map_err(|e| {
+                                    error!("error sending response body records: {}", e);
+                                })
+                                .map(|(_sink, _stream)| {
+                                    debug!("done sending response body records");
+                                })
+                                .instrument(body_span));
+
+                        // Start the response!
+                        Message::WithBody(first_record, body)
+                    },
+                    None => {
+                        warn!("no response records received");
+
+                        let (body_sender, body) = Body::<FastcgiRecord, io::Error>::pair();
+
+                        handle.spawn(
+                            body_sender.send_all(end_records.then(Ok))
+                                .map_err(|e| {
+                                    error!("error sending response body records: {}", e);
+                                })
+                                .map(|(_sink, _stream)| {
+                                    debug!("done sending response body records");
+                                })
+                                .instrument(body_span));
+
+                        Message::WithBody(
                             FastcgiRecord {
                                 request_id: id,
-                                body: FastcgiRecordBody::EndRequest(EndRequest {
-                                    app_status: 0,
-                                    protocol_status: ProtocolStatus::RequestComplete,
-                                })
-                            },
-                        ];
-
-                        // TODO: what if `handle()` returns `None`?
-                        let reactor_handle = reactor_handle.handle().unwrap();
-
-                        match maybe_record {
-                            Some(Some(first_record)) => {
-                                debug!("first record received");
-
-                                let records = record_stream
-                                    .map(|maybe_record| maybe_record.unwrap())
-                                    .chain(stream::iter_ok(end_records))
-                                    .then(Ok);
-
-                                let (body_sender, body) = Body::<FastcgiRecord, io::Error>::pair();
-
-                                // Schedule a future to pump remaining records through to the body.
-                                reactor_handle.spawn(
-                                    body_sender.send_all(records)
-                                        .map_err(|e| {
-                                            error!("error sending response body records: {}", e);
-                                        })
-                                        .map(|(_sink, _stream)| {
-                                            debug!("done sending response body records");
-                                        }));
-
-                                // Start the response!
-                                Message::WithBody(first_record, body)
+                                // Send a header-body separator (i.e. send zero headers).
+                                body: FastcgiRecordBody::Stdout(
+                                    BytesMut::from(b"\r\n".to_vec())),
                             },
-                            None => {
-                                warn!("no response records received");
-
-                                let (body_sender, body) = Body::<FastcgiRecord, io::Error>::pair();
-
-                                reactor_handle.spawn(
-                                    body_sender.send_all(stream::iter_ok(end_records).then(Ok))
-                                        .map_err(|e| {
-                                            error!("error sending response body records: {}", e);
-                                        })
-                                        .map(|(_sink, _stream)| {
-                                            debug!("done sending response body records");
-                                        }));
-
-                                Message::WithBody(
-                                    FastcgiRecord {
-                                        request_id: id,
-                                        // Send a header-body separator (i.e. send zero headers).
-                                        body: FastcgiRecordBody::Stdout(
-                                            BytesMut::from(b"\r\n".to_vec())),
-                                    },
-                                    body)
-                            },
-                            Some(None) => unreachable!()
-                        }
-                    })
+                            body)
+                    },
+                    Some(None) => unreachable!()
+                }
             });
 
-        Box::new(response_future)
+        Box::new(response_future.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::{app_status, begin_request, end_records, params, read_until_closed,
+                                read_until_ended, record, serve_handler, stream, STDERR, STDIN,
+                                STDOUT};
+
+    use tokio_core::reactor::Core;
+
+    use std::io::Write;
+
+    fn fail(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        drop(request);
+        Box::new(future::err(io::Error::new(io::ErrorKind::Other, "boom")))
+    }
+
+    fn fail_after_headers(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let mut response = request.response();
+        response.set_header("Content-Type", "text/plain");
+        Box::new(response.send_headers().and_then(|body| {
+            drop(body);
+            Err(io::Error::new(io::ErrorKind::Other, "boom"))
+        }))
+    }
+
+    /// Panics when called for `/panic`, or from its future for `/panic-later`, and otherwise
+    /// responds with "ok".
+    fn panicky(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        match request.params.get("REQUEST_URI").map(|uri| uri.as_str()) {
+            Some("/panic") => panic!("panicked in call"),
+            Some("/panic-later") => Box::new(future::lazy(|| -> io::Result<()> {
+                panic!("panicked in poll")
+            })),
+            _ => Box::new(request.response().send_headers().and_then(|mut body| {
+                body.buffer.extend_from_slice(b"ok");
+                body.finish()
+            })),
+        }
+    }
+
+    /// One request which doesn't keep the connection.
+    fn single_request() -> Vec<u8> {
+        let mut out = begin_request(1, false);
+        out.extend(params(1, &[]));
+        out.extend(record(STDIN, 1, b""));
+        out
+    }
+
+    /// Report an error for request 1, returning the stdout and stderr sent.
+    fn report(core: &mut Core, state: &Arc<RequestState>, status: u16) -> (String, String) {
+        let (sender, receiver) = mpsc::channel(64);
+        let error = io::Error::new(io::ErrorKind::Other, "boom");
+        core.run(send_error(1, sender, state, &ErrorPage::default(), status, &error)).unwrap();
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        for record in receiver.collect().wait().unwrap() {
+            match record.body {
+                FastcgiRecordBody::Stdout(data) => stdout.extend_from_slice(&data),
+                FastcgiRecordBody::Stderr(data) => stderr.extend_from_slice(&data),
+                body => panic!("unexpected record {:?}", body),
+            }
+        }
+        (String::from_utf8(stdout).unwrap(), String::from_utf8(stderr).unwrap())
+    }
+
+    #[test]
+    fn error_page_before_headers() {
+        let mut core = Core::new().unwrap();
+        let state = Arc::new(RequestState::new(core.remote()));
+        let (stdout, stderr) = report(&mut core, &state, 503);
+        assert!(stdout.contains("Status: 503 Service Unavailable\r\n"), "{:?}", stdout);
+        assert!(stdout.contains("Content-Type: text/html\r\n"), "{:?}", stdout);
+        assert!(stdout.ends_with("<h1>503 Service Unavailable</h1></body></html>\n"));
+        assert_eq!(stderr, "request 1 failed: boom\n");
+        assert!(state.headers_sent());
+        assert_eq!(state.app_status(), 1);
+    }
+
+    #[test]
+    fn error_reported_once() {
+        let mut core = Core::new().unwrap();
+        let state = Arc::new(RequestState::new(core.remote()));
+        let (_, stderr) = report(&mut core, &state, 500);
+        assert_eq!(stderr, "request 1 failed: boom\n");
+        assert_eq!(report(&mut core, &state, 500), (String::new(), String::new()));
+        assert_eq!(state.app_status(), 1);
+    }
+
+    #[test]
+    fn no_error_page_after_headers() {
+        let mut core = Core::new().unwrap();
+        let state = Arc::new(RequestState::new(core.remote()));
+        state.set_headers_sent();
+        let (stdout, stderr) = report(&mut core, &state, 500);
+        assert_eq!(stdout, "");
+        assert_eq!(stderr, "request 1 failed: boom\n");
+        assert_eq!(state.app_status(), 1);
+    }
+
+    #[test]
+    fn failed_handler() {
+        let mut client = serve_handler(fail);
+        client.write_all(&single_request()).unwrap();
+        let out = read_until_closed(&mut client);
+        assert!(stream(&out, STDOUT, 1).starts_with("Status: 500 Internal Server Error\r\n"));
+        assert_eq!(stream(&out, STDERR, 1), "request 1 failed: boom\n");
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+        assert_eq!(app_status(&out, 1), Some(1));
+    }
+
+    #[test]
+    fn handler_panics() {
+        let mut client = serve_handler(panicky);
+        let mut requests = vec![];
+        for (id, uri) in [(1, "/panic"), (2, "/ok"), (3, "/panic-later"), (4, "/ok")].iter() {
+            requests.extend(begin_request(*id, true));
+            requests.extend(params(*id, &[("REQUEST_URI", uri)]));
+        }
+        for id in 1 .. 5 {
+            requests.extend(record(STDIN, id, b""));
+        }
+        client.write_all(&requests).unwrap();
+        let out = read_until_ended(&mut client, 4);
+        for &(id, msg) in [(1, "panicked in call"), (3, "panicked in poll")].iter() {
+            assert!(stream(&out, STDOUT, id).starts_with("Status: 500 Internal Server Error\r\n"));
+            assert_eq!(stream(&out, STDERR, id),
+                       format!("request {} failed: handler panicked: {}\n", id, msg));
+            assert_eq!(app_status(&out, id), Some(1));
+        }
+        for &id in [2, 4].iter() {
+            assert!(stream(&out, STDOUT, id).ends_with("\r\n\r\nok"));
+            assert_eq!(stream(&out, STDERR, id), "");
+            assert_eq!(app_status(&out, id), Some(0));
+        }
+    }
+
+    #[test]
+    fn failed_handler_after_headers() {
+        let mut client = serve_handler(fail_after_headers);
+        client.write_all(&single_request()).unwrap();
+        let out = read_until_closed(&mut client);
+        let stdout = stream(&out, STDOUT, 1);
+        assert!(stdout.contains("Content-Type: text/plain\r\n"), "{:?}", stdout);
+        assert!(!stdout.contains("Status:") && stdout.ends_with("\r\n\r\n"), "{:?}", stdout);
+        assert_eq!(stream(&out, STDERR, 1), "request 1 failed: boom\n");
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+        assert_eq!(app_status(&out, 1), Some(1));
     }
 }
@@ -0,0 +1,230 @@
+//! Shutting a server down gracefully: stop taking new connections, let the requests in flight
+//! finish, and at a deadline, end whatever is left.
+
+use futures::{future, Async, Future};
+#[cfg(feature = "signals")]
+use futures::Stream;
+use futures::future::{Either, Shared};
+use futures::sync::oneshot;
+#[cfg(feature = "signals")]
+use libc;
+#[cfg(feature = "signals")]
+use tokio_signal::unix::Signal;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tells the parts of a server when it's shutting down. Cloned into each connection's transport
+/// and service.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    draining: Shared<oneshot::Receiver<()>>,
+    deadline: Shared<oneshot::Receiver<()>>,
+    limit: Option<Arc<RequestLimit>>,
+}
+
+/// The number of requests a server may still take before it should shut down, shared by all its
+/// threads.
+#[derive(Debug)]
+pub struct RequestLimit {
+    max_requests: usize,
+    left: AtomicUsize,
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    reached: Shared<oneshot::Receiver<()>>,
+}
+
+impl RequestLimit {
+    pub fn new(max_requests: usize) -> RequestLimit {
+        let (sender, reached) = oneshot::channel();
+        let limit = RequestLimit {
+            max_requests,
+            left: AtomicUsize::new(max_requests),
+            sender: Mutex::new(Some(sender)),
+            reached: reached.shared(),
+        };
+        if max_requests == 0 {
+            limit.fire();
+        }
+        limit
+    }
+
+    /// Count a request.
+    pub fn request_started(&self) {
+        let left = self.left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+            left.checked_sub(1)
+        });
+        if left == Ok(1) {
+            info!("took {} requests; shutting down", self.max_requests);
+            self.fire();
+        }
+    }
+
+    fn fire(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// A future which completes once the limit is reached.
+    pub fn reached(&self) -> Box<dyn Future<Item=(), Error=()>> {
+        fired(&self.reached)
+    }
+}
+
+/// Starts the shutdown of everything with the matching `Shutdown`.
+pub struct ShutdownTrigger {
+    draining: Option<oneshot::Sender<()>>,
+    deadline: Option<oneshot::Sender<()>>,
+}
+
+impl ShutdownTrigger {
+    /// Start draining: stop taking new connections, and close connections once they have no
+    /// requests in flight.
+    pub fn drain(&mut self) {
+        if let Some(sender) = self.draining.take() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// The deadline is up: end all the requests still in flight. This starts draining too, if it
+    /// hasn't already.
+    pub fn force(&mut self) {
+        self.drain();
+        if let Some(sender) = self.deadline.take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// A future of when the sender fires, which never completes if it's dropped instead.
+fn fired(receiver: &Shared<oneshot::Receiver<()>>) -> Box<dyn Future<Item=(), Error=()>> {
+    Box::new(receiver.clone().then(|result| match result {
+        Ok(_) => Either::A(future::ok(())),
+        Err(_) => Either::B(future::empty()),
+    }))
+}
+
+impl Shutdown {
+    /// Make a `Shutdown`, and the trigger for it.
+    pub fn channel() -> (ShutdownTrigger, Shutdown) {
+        let (draining_sender, draining) = oneshot::channel();
+        let (deadline_sender, deadline) = oneshot::channel();
+        let trigger = ShutdownTrigger {
+            draining: Some(draining_sender),
+            deadline: Some(deadline_sender),
+        };
+        let shutdown = Shutdown {
+            draining: draining.shared(),
+            deadline: deadline.shared(),
+            limit: None,
+        };
+        (trigger, shutdown)
+    }
+
+    /// Whether draining has started. If it hasn't, the current task is woken when it does.
+    pub fn poll_draining(&mut self) -> bool {
+        match self.draining.poll() {
+            Ok(Async::Ready(_)) => true,
+            Ok(Async::NotReady) | Err(_) => false,
+        }
+    }
+
+    /// A future which completes when draining starts.
+    pub fn draining(&self) -> Box<dyn Future<Item=(), Error=()>> {
+        fired(&self.draining)
+    }
+
+    /// A future which completes when the deadline is up.
+    pub fn deadline(&self) -> Box<dyn Future<Item=(), Error=()>> {
+        fired(&self.deadline)
+    }
+
+    /// Count the requests the server takes against a limit, for recycling worker processes. Set
+    /// this before cloning the `Shutdown`.
+    pub fn with_request_limit(mut self, limit: Arc<RequestLimit>) -> Shutdown {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Count a request against the request limit, if there is one.
+    pub fn request_started(&self) {
+        if let Some(ref limit) = self.limit {
+            limit.request_started();
+        }
+    }
+}
+
+/// The error a request fails with when the server shuts down before it's finished. It is wrapped
+/// in an `io::Error` of kind `Interrupted`.
+#[derive(Debug)]
+pub struct ShutdownError;
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the server shut down before the request finished")
+    }
+}
+
+impl Error for ShutdownError {}
+
+/// The HTTP status to respond with when a request failed because the server shut down: 503.
+pub fn shutdown_status(error: &io::Error) -> Option<u16> {
+    error.get_ref()
+        .and_then(|e| e.downcast_ref::<ShutdownError>())
+        .map(|_| 503)
+}
+
+/// Fail the future with a `ShutdownError` if it doesn't complete before the shutdown deadline.
+pub fn with_deadline<F>(future: F, shutdown: &Shutdown)
+    -> Box<dyn Future<Item=F::Item, Error=io::Error>>
+    where F: Future<Error=io::Error> + 'static
+{
+    Box::new(future.select2(shutdown.deadline()).then(|result| {
+        match result {
+            Ok(Either::A((item, _deadline))) => Ok(item),
+            Err(Either::A((e, _deadline))) => Err(e),
+            Ok(Either::B(((), _future))) | Err(Either::B(((), _future))) => {
+                Err(io::Error::new(io::ErrorKind::Interrupted, ShutdownError))
+            },
+        }
+    }))
+}
+
+/// A future which completes when the process gets `SIGTERM` or `SIGINT`, for use as the shutdown
+/// signal of a `FastcgiServer`.
+#[cfg(feature = "signals")]
+pub fn termination_signal() -> Box<dyn Future<Item=(), Error=()>> {
+    let signal = |signal: libc::c_int| {
+        Signal::new(signal)
+            .flatten_stream()
+            .into_future()
+            .then(move |result| match result {
+                Ok(_) => {
+                    info!("got signal {}", signal);
+                    Either::A(future::ok::<(), ()>(()))
+                },
+                Err((e, _)) => {
+                    error!("failed to listen for signal {}: {}", signal, e);
+                    Either::B(future::empty())
+                },
+            })
+    };
+    Box::new(signal(libc::SIGTERM).select(signal(libc::SIGINT))
+        .map(|_| ())
+        .map_err(|_| ()))
+}
+
+/// What `FastcgiServer::run` shuts down on: `termination_signal` with the `signals` feature, and
+/// otherwise nothing.
+#[cfg(feature = "signals")]
+pub fn run_signal() -> Box<dyn Future<Item=(), Error=()>> {
+    termination_signal()
+}
+
+#[cfg(not(feature = "signals"))]
+pub fn run_signal() -> Box<dyn Future<Item=(), Error=()>> {
+    Box::new(future::empty())
+}
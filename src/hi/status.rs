@@ -0,0 +1,140 @@
+//! HTTP status codes, as sent to the web server in the CGI `Status` pseudo-header.
+
+/// Whether the code can be sent as an HTTP status, which takes exactly three digits, starting at
+/// 100.
+pub fn is_valid_status(code: u16) -> bool {
+    (100..=999).contains(&code)
+}
+
+/// Return the standard reason phrase for the given HTTP status code, if it has one.
+pub fn reason_phrase(code: u16) -> Option<&'static str> {
+    let phrase = match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        102 => "Processing",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        208 => "Already Reported",
+        226 => "IM Used",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        418 => "I'm a teapot",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Entity",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        510 => "Not Extended",
+        511 => "Network Authentication Required",
+        _ => return None,
+    };
+    Some(phrase)
+}
+
+/// Format the value of a `Status` header for the given code: the code followed by its reason
+/// phrase. Codes without a standard phrase get a generic one based on their class.
+pub fn status_line(code: u16) -> String {
+    let phrase = reason_phrase(code).unwrap_or(match code / 100 {
+        1 => "Informational",
+        2 => "Success",
+        3 => "Redirection",
+        4 => "Client Error",
+        _ => "Server Error",
+    });
+    format!("{} {}", code, phrase)
+}
+
+/// Parse the status code out of the value of a `Status` header, if it's a valid one.
+pub fn parse_status(value: &str) -> Option<u16> {
+    value.trim_start().split(' ').next()
+        .and_then(|code| code.parse().ok())
+        .filter(|&code| is_valid_status(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_statuses() {
+        assert!(is_valid_status(100));
+        assert!(is_valid_status(404));
+        assert!(is_valid_status(999));
+        assert!(!is_valid_status(0));
+        assert!(!is_valid_status(99));
+        assert!(!is_valid_status(1000));
+    }
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(status_line(200), "200 OK");
+        assert_eq!(status_line(404), "404 Not Found");
+        assert_eq!(status_line(299), "299 Success");
+        assert_eq!(status_line(499), "499 Client Error");
+        assert_eq!(status_line(599), "599 Server Error");
+    }
+
+    #[test]
+    fn parse_statuses() {
+        assert_eq!(parse_status("200 OK"), Some(200));
+        assert_eq!(parse_status("  404 Not Found"), Some(404));
+        assert_eq!(parse_status("503"), Some(503));
+        assert_eq!(parse_status("OK"), None);
+        assert_eq!(parse_status(""), None);
+        assert_eq!(parse_status("0 Nothing"), None);
+        assert_eq!(parse_status("1000 Too Big"), None);
+        assert_eq!(parse_status("70000"), None);
+    }
+
+    #[test]
+    fn status_lines_parse_back() {
+        for code in 100..1000 {
+            assert_eq!(parse_status(&status_line(code)), Some(code));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Socket activation and readiness notification for running as a systemd service.
+
+use libc;
+
+use std::env;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The first file descriptor systemd passes sockets on.
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Set once `listen_fds` has taken the sockets, so that they aren't used twice.
+static FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A socket passed in by systemd.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenFd {
+    pub fd: RawFd,
+    /// The socket's name from `FileDescriptorName=` in the socket unit, if set.
+    pub name: Option<String>,
+}
+
+fn invalid_env(name: &str, value: &str) -> io::Error {
+    let msg = format!("invalid {} value {:?}", name, value);
+    error!("{}", msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Whether systemd passed this process any sockets, which haven't been taken yet.
+pub fn is_socket_activated() -> bool {
+    if FDS_TAKEN.load(Ordering::SeqCst) {
+        return false;
+    }
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok());
+    pid == Some(process::id()) && fds.unwrap_or(0) > 0
+}
+
+/// Get the sockets systemd passed this process, from `LISTEN_FDS`, `LISTEN_PID` and
+/// `LISTEN_FDNAMES`, like `sd_listen_fds_with_names`. If the variables aren't set, or are meant
+/// for another process, there are none.
+///
+/// If `take` is set, the sockets are taken, so that later calls, and `is_socket_activated`, find
+/// none. The variables are left in the environment, since changing it isn't safe while other
+/// threads may be reading it; remove them from the environment of any child processes instead,
+/// with `Command::env_remove`.
+pub fn listen_fds(take: bool) -> io::Result<Vec<ListenFd>> {
+    let fds = take_listen_fds(&FDS_TAKEN, take, env::var("LISTEN_PID").ok(),
+                              env::var("LISTEN_FDS").ok(), env::var("LISTEN_FDNAMES").ok())?;
+    for listen_fd in &fds {
+        if unsafe { libc::fcntl(listen_fd.fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(fds)
+}
+
+/// The sockets described by the values of `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`,
+/// unless `taken` says they've already been taken, taking them if `take` is set.
+fn take_listen_fds(taken: &AtomicBool, take: bool, pid: Option<String>, fds: Option<String>,
+                   names: Option<String>)
+    -> io::Result<Vec<ListenFd>>
+{
+    let taken = if take {
+        taken.swap(true, Ordering::SeqCst)
+    } else {
+        taken.load(Ordering::SeqCst)
+    };
+    if taken {
+        debug!("systemd's sockets have already been taken");
+        return Ok(vec![]);
+    }
+
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(vec![]),
+    };
+    if pid.parse::<u32>().map_err(|_| invalid_env("LISTEN_PID", &pid))? != process::id() {
+        debug!("LISTEN_PID is {}, not this process", pid);
+        return Ok(vec![]);
+    }
+    let count = fds.parse::<RawFd>().map_err(|_| invalid_env("LISTEN_FDS", &fds))?;
+    let names: Vec<&str> = names.as_ref()
+        .map(|names| names.split(':').collect())
+        .unwrap_or_default();
+
+    Ok((0 .. count)
+        .map(|i| ListenFd {
+            fd: SD_LISTEN_FDS_START + i,
+            name: names.get(i as usize).map(|name| (*name).to_owned()),
+        })
+        .collect())
+}
+
+/// Send a state change like `READY=1` or `STOPPING=1` to systemd over `NOTIFY_SOCKET`, like
+/// `sd_notify`. Returns whether there was a socket to send it to.
+pub fn sd_notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    if path.as_bytes().first() == Some(&b'@') {
+        send_abstract(&socket, &path.as_bytes()[1..], state)?;
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    debug!("sent {:?} to systemd", state);
+    Ok(true)
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &[u8], _state: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "abstract NOTIFY_SOCKET addresses are only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(taken: &AtomicBool, take: bool, pid: &str, fds: &str, names: Option<&str>)
+        -> io::Result<Vec<ListenFd>>
+    {
+        take_listen_fds(taken, take, Some(pid.to_owned()), Some(fds.to_owned()),
+                        names.map(str::to_owned))
+    }
+
+    fn listen_fd(fd: RawFd, name: Option<&str>) -> ListenFd {
+        ListenFd { fd, name: name.map(str::to_owned) }
+    }
+
+    #[test]
+    fn names() {
+        let pid = process::id().to_string();
+        let fds = take(&AtomicBool::new(false), false, &pid, "3", Some("web:admin")).unwrap();
+        assert_eq!(fds, vec![listen_fd(3, Some("web")), listen_fd(4, Some("admin")),
+                             listen_fd(5, None)]);
+        let fds = take(&AtomicBool::new(false), false, &pid, "1", None).unwrap();
+        assert_eq!(fds, vec![listen_fd(3, None)]);
+    }
+
+    #[test]
+    fn not_activated() {
+        let taken = AtomicBool::new(false);
+        assert!(take_listen_fds(&taken, false, None, None, None).unwrap().is_empty());
+        assert!(take_listen_fds(&taken, false, Some(process::id().to_string()), None, None)
+                    .unwrap().is_empty());
+        let other = (process::id() + 1).to_string();
+        assert!(take(&taken, false, &other, "1", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        let taken = AtomicBool::new(false);
+        let e = take(&taken, false, "systemd", "1", None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = take(&taken, false, &process::id().to_string(), "one", None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn taken() {
+        let pid = process::id().to_string();
+        let taken = AtomicBool::new(false);
+        assert_eq!(take(&taken, false, &pid, "1", None).unwrap().len(), 1);
+        assert!(!taken.load(Ordering::SeqCst));
+        assert_eq!(take(&taken, true, &pid, "1", None).unwrap().len(), 1);
+        assert!(taken.load(Ordering::SeqCst));
+        assert!(take(&taken, true, &pid, "1", None).unwrap().is_empty());
+        assert!(take(&taken, false, &pid, "1", None).unwrap().is_empty());
+    }
+}
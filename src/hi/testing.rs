@@ -0,0 +1,146 @@
+//! Helpers for tests which talk FastCGI to a service over a socket, the way a web server would.
+
+use super::super::*;
+
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_uds::UnixStream;
+
+use std::io::Read;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const BEGIN_REQUEST: u8 = 1;
+pub const END_REQUEST: u8 = 3;
+pub const PARAMS: u8 = 4;
+pub const STDIN: u8 = 5;
+pub const STDOUT: u8 = 6;
+pub const STDERR: u8 = 7;
+
+pub fn record(record_type: u8, id: u16, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![1, record_type, (id >> 8) as u8, id as u8,
+                       (content.len() >> 8) as u8, content.len() as u8, 0, 0];
+    out.extend_from_slice(content);
+    out
+}
+
+/// A responder's BeginRequest record.
+pub fn begin_request(id: u16, keep_conn: bool) -> Vec<u8> {
+    record(BEGIN_REQUEST, id, &[0, 1, keep_conn as u8, 0, 0, 0, 0, 0])
+}
+
+/// Params records with the given (short) params, followed by the empty one ending them.
+pub fn params(id: u16, params: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = vec![];
+    for &(name, value) in params {
+        let mut content = vec![name.len() as u8, value.len() as u8];
+        content.extend_from_slice(name.as_bytes());
+        content.extend_from_slice(value.as_bytes());
+        out.extend(record(PARAMS, id, &content));
+    }
+    out.extend(record(PARAMS, id, b""));
+    out
+}
+
+/// A responder request with no params or body, which asks to keep the connection.
+pub fn request(id: u16) -> Vec<u8> {
+    let mut out = begin_request(id, true);
+    out.extend(record(PARAMS, id, b""));
+    out.extend(record(STDIN, id, b""));
+    out
+}
+
+/// The type, request ID and content of each record in the output.
+pub fn records(mut data: &[u8]) -> Vec<(u8, u16, Vec<u8>)> {
+    let mut records = vec![];
+    while data.len() >= 8 {
+        let id = (u16::from(data[2]) << 8) | u16::from(data[3]);
+        let len = ((data[4] as usize) << 8) | data[5] as usize;
+        let end = 8 + len + data[6] as usize;
+        records.push((data[1], id, data[8 .. 8 + len].to_vec()));
+        data = &data[end ..];
+    }
+    records
+}
+
+/// The content of all the records of one type for a request, as text.
+pub fn stream(data: &[u8], record_type: u8, id: u16) -> String {
+    let content = records(data).into_iter()
+        .filter(|&(t, i, _)| t == record_type && i == id)
+        .flat_map(|(_, _, content)| content)
+        .collect::<Vec<u8>>();
+    String::from_utf8(content).unwrap()
+}
+
+/// The request IDs and protocol statuses of the end records in the output.
+pub fn end_records(data: &[u8]) -> Vec<(u16, u8)> {
+    records(data).into_iter()
+        .filter(|&(t, _, _)| t == END_REQUEST)
+        .map(|(_, id, content)| (id, content[4]))
+        .collect()
+}
+
+/// The app status in a request's end record.
+pub fn app_status(data: &[u8], id: u16) -> Option<u32> {
+    records(data).into_iter()
+        .find(|&(t, i, _)| t == END_REQUEST && i == id)
+        .map(|(_, _, c)| {
+            (u32::from(c[0]) << 24) | (u32::from(c[1]) << 16) | (u32::from(c[2]) << 8)
+                | u32::from(c[3])
+        })
+}
+
+/// Serve a connection on a thread of its own, with a reactor running for a while, returning the
+/// other end of it. The function binds a service to the connection.
+pub fn serve<F>(bind: F) -> StdUnixStream
+    where F: FnOnce(&Handle, UnixStream) + Send + 'static
+{
+    let (client, server) = StdUnixStream::pair().unwrap();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let io = UnixStream::from_std(server, handle.new_tokio_handle()).unwrap();
+        bind(&handle, io);
+        let _ = core.run(Timeout::new(Duration::from_secs(5), &handle).unwrap());
+    });
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+/// Serve a connection with the service the function makes, and the default proto.
+pub fn serve_service<F, H>(service: F) -> StdUnixStream
+    where F: FnOnce(&Handle) -> FastcgiService<H> + Send + 'static,
+          H: FastcgiRequestHandler + 'static
+{
+    serve(move |handle, io| {
+        let service = service(handle);
+        FastcgiProto::new().bind_service(handle, io, service);
+    })
+}
+
+/// Serve a connection with the handler, with the service's default settings.
+pub fn serve_handler<H>(handler: H) -> StdUnixStream
+    where H: FastcgiRequestHandler + 'static
+{
+    serve_service(move |handle| FastcgiService::new(handle.remote().clone(), Arc::new(handler)))
+}
+
+pub fn read_until_closed(client: &mut StdUnixStream) -> Vec<u8> {
+    let mut out = vec![];
+    client.read_to_end(&mut out).expect("the connection wasn't closed");
+    out
+}
+
+
+/// Read from a connection which stays open until there are this many end records.
+pub fn read_until_ended(client: &mut StdUnixStream, requests: usize) -> Vec<u8> {
+    let mut out = vec![];
+    let mut buf = [0; 4096];
+    while end_records(&out).len() < requests {
+        let read = client.read(&mut buf).expect("the requests didn't end");
+        assert!(read != 0, "the connection was closed");
+        out.extend_from_slice(&buf[.. read]);
+    }
+    out
+}
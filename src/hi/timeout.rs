@@ -0,0 +1,255 @@
+//! Deadlines for the phases of a request, so that a stalled web server or a handler that never
+//! finishes can't hold on to a request forever.
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::future::Either;
+use tokio_core::reactor::{Handle, Timeout};
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// How long each phase of a request may take. `None` means no limit, which is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timeouts {
+    /// From the `BeginRequest` record until the end of the params.
+    pub params: Option<Duration>,
+
+    /// From the end of the params until the end of the request body. This only applies while the
+    /// handler is reading the body: reading it fails once the time is up.
+    pub body: Option<Duration>,
+
+    /// From calling the handler until its future completes.
+    pub handler: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Phase {
+    Params,
+    Body,
+    Handler,
+}
+
+/// The error a request fails with when one of its phases times out. It is wrapped in an
+/// `io::Error` of kind `TimedOut`.
+#[derive(Debug)]
+pub struct TimeoutError {
+    pub phase: Phase,
+    pub duration: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.phase {
+            Phase::Params => "reading the params",
+            Phase::Body => "reading the request body",
+            Phase::Handler => "running the handler",
+        };
+        write!(f, "timed out after {:?} {}", self.duration, what)
+    }
+}
+
+impl Error for TimeoutError {}
+
+fn timed_out(phase: Phase, duration: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, TimeoutError { phase, duration })
+}
+
+/// The HTTP status to respond with when a request failed because of a timeout: 503 when the web
+/// server didn't send the request in time, and 504 when the handler didn't respond in time.
+pub fn timeout_status(error: &io::Error) -> Option<u16> {
+    error.get_ref()
+        .and_then(|e| e.downcast_ref::<TimeoutError>())
+        .map(|e| match e.phase {
+            Phase::Params | Phase::Body => 503,
+            Phase::Handler => 504,
+        })
+}
+
+/// Fail the future with a `TimeoutError` for the given phase if it doesn't complete in time.
+pub fn with_timeout<F>(future: F, phase: Phase, duration: Option<Duration>, handle: &Handle)
+    -> Box<dyn Future<Item=F::Item, Error=io::Error>>
+    where F: Future<Error=io::Error> + 'static
+{
+    let duration = match duration {
+        Some(duration) => duration,
+        None => return Box::new(future),
+    };
+    let timeout = match Timeout::new(duration, handle) {
+        Ok(timeout) => timeout,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    Box::new(future.select2(timeout).then(move |result| {
+        match result {
+            Ok(Either::A((item, _timeout))) => Ok(item),
+            Err(Either::A((e, _timeout))) => Err(e),
+            Ok(Either::B(((), _future))) => Err(timed_out(phase, duration)),
+            Err(Either::B((e, _future))) => Err(e),
+        }
+    }))
+}
+
+/// A request body stream which fails with a `TimeoutError` if it doesn't end in time.
+pub struct BodyTimeout<S> {
+    inner: S,
+    timeout: Option<Timeout>,
+    duration: Duration,
+}
+
+impl<S: Stream<Error=io::Error>> BodyTimeout<S> {
+    pub fn new(inner: S, duration: Duration, handle: &Handle) -> io::Result<BodyTimeout<S>> {
+        Ok(BodyTimeout {
+            inner,
+            timeout: Some(Timeout::new(duration, handle)?),
+            duration,
+        })
+    }
+}
+
+impl<S: Stream<Error=io::Error>> Stream for BodyTimeout<S> {
+    type Item = S::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, io::Error> {
+        match self.inner.poll()? {
+            Async::Ready(None) => {
+                self.timeout = None;
+                return Ok(Async::Ready(None));
+            },
+            Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+            Async::NotReady => (),
+        }
+        let expired = match self.timeout {
+            Some(ref mut timeout) => timeout.poll()?.is_ready(),
+            None => false,
+        };
+        if expired {
+            self.timeout = None;
+            return Err(timed_out(Phase::Body, self.duration));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::*;
+    use super::super::testing::*;
+
+    use futures::stream;
+    use tokio_core::reactor::Core;
+
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn never_finish(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        drop(request);
+        Box::new(future::empty())
+    }
+
+    fn read_body(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let response = request.response();
+        Box::new(request.body.collect().and_then(|_body| {
+            response.send_headers().and_then(|body| body.finish())
+        }))
+    }
+
+    /// Serve the handler with the timeouts, and send it a request which goes as far as `sent`.
+    fn time_out<H>(handler: H, timeouts: Timeouts, sent: &[u8]) -> Vec<u8>
+        where H: FastcgiRequestHandler + 'static
+    {
+        let mut client = serve_service(move |handle| {
+            FastcgiService::new(handle.remote().clone(), Arc::new(handler))
+                .with_timeouts(timeouts)
+        });
+        client.write_all(sent).unwrap();
+        read_until_closed(&mut client)
+    }
+
+    fn status_of(error: &io::Error) -> Option<u16> {
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        timeout_status(error)
+    }
+
+    #[test]
+    fn future_timeout() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let duration = Some(Duration::from_millis(50));
+
+        let done = with_timeout(future::ok(1), Phase::Handler, duration, &handle);
+        assert_eq!(core.run(done).unwrap(), 1);
+
+        let stalled = with_timeout(future::empty::<(), _>(), Phase::Handler, duration, &handle);
+        let error = core.run(stalled).unwrap_err();
+        assert_eq!(status_of(&error), Some(504));
+        assert_eq!(error.to_string(), "timed out after 50ms running the handler");
+    }
+
+    #[test]
+    fn body_timeout() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let duration = Duration::from_millis(50);
+
+        let ended = BodyTimeout::new(stream::iter_ok(vec![1, 2]), duration, &handle).unwrap();
+        assert_eq!(core.run(ended.collect()).unwrap(), vec![1, 2]);
+
+        let stalled = stream::iter_ok(vec![1]).chain(future::empty().into_stream());
+        let stalled = BodyTimeout::new(stalled, duration, &handle).unwrap();
+        let (first, rest) = core.run(stalled.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(first, Some(1));
+        match core.run(rest.into_future()) {
+            Err((error, _)) => assert_eq!(status_of(&error), Some(503)),
+            Ok((item, _)) => panic!("got {:?} instead of timing out", item),
+        }
+    }
+
+    #[test]
+    fn slow_handler() {
+        let timeouts = Timeouts {
+            handler: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+        let mut sent = begin_request(1, false);
+        sent.extend(params(1, &[]));
+        sent.extend(record(STDIN, 1, b""));
+        let out = time_out(never_finish, timeouts, &sent);
+        assert!(stream(&out, STDOUT, 1).starts_with("Status: 504 Gateway Timeout\r\n"));
+        assert_eq!(stream(&out, STDERR, 1),
+                   "request 1 failed: timed out after 100ms running the handler\n");
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+        assert_eq!(app_status(&out, 1), Some(1));
+    }
+
+    #[test]
+    fn stalled_body() {
+        let timeouts = Timeouts {
+            body: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+        let mut sent = begin_request(1, false);
+        sent.extend(params(1, &[]));
+        sent.extend(record(STDIN, 1, b"part of the body"));
+        let out = time_out(read_body, timeouts, &sent);
+        assert!(stream(&out, STDOUT, 1).starts_with("Status: 503 Service Unavailable\r\n"));
+        assert_eq!(stream(&out, STDERR, 1),
+                   "request 1 failed: timed out after 100ms reading the request body\n");
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+        assert_eq!(app_status(&out, 1), Some(1));
+    }
+
+    #[test]
+    fn stalled_params() {
+        let timeouts = Timeouts {
+            params: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+        let out = time_out(never_finish, timeouts, &begin_request(1, false));
+        assert!(stream(&out, STDOUT, 1).starts_with("Status: 503 Service Unavailable\r\n"));
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+        assert_eq!(app_status(&out, 1), Some(1));
+    }
+}
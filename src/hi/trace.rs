@@ -0,0 +1,373 @@
+//! `tracing` spans for connections and requests, and trace context passed in by the web server.
+//!
+//! Without the `tracing` feature, `Span` is a stand-in that records nothing, so that the same code
+//! and API work either way.
+
+use super::super::*;
+
+use futures::{Future, Poll};
+#[cfg(feature = "tracing")]
+use tracing::field::Empty;
+
+#[cfg(feature = "tracing")]
+pub use tracing::Span;
+
+#[cfg(feature = "tracing")]
+use std::collections::HashMap;
+#[cfg(not(feature = "tracing"))]
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "tracing")]
+use std::sync::{Arc, Mutex};
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// Make a span for a new connection, numbered in the order they were made.
+///
+/// `FastcgiProto::bind_service` makes one for each connection, and gives it to both the transport
+/// and the service, so that the spans of the requests on the connection are inside it along with
+/// the connection's own events.
+#[cfg(feature = "tracing")]
+pub fn connection_span() -> Span {
+    info_span!("connection", id = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn connection_span() -> Span {
+    NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    Span::none()
+}
+
+/// Make a span for a request, inside the given connection span if there is one. The `uri`,
+/// `trace_id` and `parent_id` fields are recorded once the params have been read.
+#[cfg(feature = "tracing")]
+pub fn request_span(connection: Option<&Span>, request_id: u16, role: Role) -> Span {
+    match connection {
+        Some(parent) => info_span!(parent: parent, "request",
+            request_id, role = ?role, uri = Empty, trace_id = Empty, parent_id = Empty),
+        None => info_span!("request",
+            request_id, role = ?role, uri = Empty, trace_id = Empty, parent_id = Empty),
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn request_span(_connection: Option<&Span>, _request_id: u16, _role: Role) -> Span {
+    Span::none()
+}
+
+/// The spans of the requests in flight on a connection, by request ID. The transport makes each
+/// one when the request begins, so that the events for all of the request's records are inside it,
+/// and `FastcgiProto::bind_service` gives the same `RequestSpans` to the service, which runs the
+/// request in it.
+///
+/// Without the `tracing` feature, this keeps nothing.
+#[derive(Debug, Clone, Default)]
+pub struct RequestSpans {
+    #[cfg(feature = "tracing")]
+    spans: Arc<Mutex<HashMap<u16, Span>>>,
+}
+
+#[cfg(feature = "tracing")]
+impl RequestSpans {
+    pub fn insert(&self, request_id: u16, span: Span) {
+        self.spans.lock().unwrap().insert(request_id, span);
+    }
+
+    pub fn get(&self, request_id: u16) -> Option<Span> {
+        self.spans.lock().unwrap().get(&request_id).cloned()
+    }
+
+    pub fn remove(&self, request_id: u16) {
+        self.spans.lock().unwrap().remove(&request_id);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl RequestSpans {
+    pub fn insert(&self, _request_id: u16, _span: Span) {}
+
+    pub fn get(&self, _request_id: u16) -> Option<Span> {
+        None
+    }
+
+    pub fn remove(&self, _request_id: u16) {}
+}
+
+/// A span which records nothing, in place of `tracing::Span` without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub struct Span {
+    _private: (),
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn none() -> Span {
+        Span { _private: () }
+    }
+
+    pub fn enter(&self) -> Entered<'_> {
+        Entered(PhantomData)
+    }
+
+    pub fn record<V>(&self, _field: &str, _value: V) -> &Span {
+        self
+    }
+}
+
+/// The guard `Span::enter` returns, without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub struct Entered<'a>(PhantomData<&'a Span>);
+
+/// A future which is polled inside a span.
+pub struct Instrumented<F> {
+    inner: F,
+    span: Span,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let _entered = self.span.enter();
+        self.inner.poll()
+    }
+}
+
+/// Polling futures inside a span.
+pub trait Instrument: Future + Sized {
+    fn instrument(self, span: Span) -> Instrumented<Self> {
+        Instrumented { inner: self, span }
+    }
+}
+
+impl<F: Future> Instrument for F {}
+
+/// Trace context from a W3C `traceparent` header, which the web server passes on in the
+/// `HTTP_TRACEPARENT` param.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceParent {
+    pub version: u8,
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// The ID of the caller's span: 16 lowercase hex digits.
+    pub parent_id: String,
+    pub flags: u8,
+}
+
+impl TraceParent {
+    /// Parse a `traceparent` header value, returning `None` if it isn't valid.
+    pub fn parse(value: &str) -> Option<TraceParent> {
+        fn is_hex(s: &str, len: usize) -> bool {
+            s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        }
+
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 || !is_hex(parts[0], 2) || !is_hex(parts[3], 2) {
+            return None;
+        }
+        let version = u8::from_str_radix(parts[0], 16).ok()?;
+        // Version 0 has exactly four fields; later versions may add more after them.
+        if version == 0xff || (version == 0 && parts.len() != 4) {
+            return None;
+        }
+        let (trace_id, parent_id) = (parts[1], parts[2]);
+        if !is_hex(trace_id, 32) || !is_hex(parent_id, 16)
+            || trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0')
+        {
+            return None;
+        }
+        Some(TraceParent {
+            version,
+            trace_id: trace_id.to_owned(),
+            parent_id: parent_id.to_owned(),
+            flags: u8::from_str_radix(parts[3], 16).ok()?,
+        })
+    }
+
+    /// Whether the caller may have recorded its trace.
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tracing")]
+    use super::super::testing::{read_until_ended, request, serve};
+
+    #[cfg(feature = "tracing")]
+    use tracing::{Event, Id, Metadata, Subscriber};
+    #[cfg(feature = "tracing")]
+    use tracing::field::{Field, Visit};
+    #[cfg(feature = "tracing")]
+    use tracing::span::{Attributes, Record};
+
+    #[cfg(feature = "tracing")]
+    use std::fmt::Debug;
+    #[cfg(feature = "tracing")]
+    use std::io::{self, Write};
+    #[cfg(feature = "tracing")]
+    use std::mem;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn parse_valid() {
+        let value = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        let parent = TraceParent::parse(&value).unwrap();
+        assert_eq!(parent.version, 0);
+        assert_eq!(parent.trace_id, TRACE_ID);
+        assert_eq!(parent.parent_id, PARENT_ID);
+        assert_eq!(parent.flags, 1);
+        assert!(parent.sampled());
+
+        let parent = TraceParent::parse(&format!(" 00-{}-{}-00\n", TRACE_ID, PARENT_ID)).unwrap();
+        assert!(!parent.sampled());
+    }
+
+    #[test]
+    fn parse_later_versions() {
+        // Later versions may add fields, which are ignored.
+        let parent = TraceParent::parse(&format!("01-{}-{}-03-extra", TRACE_ID, PARENT_ID));
+        assert_eq!(parent.map(|parent| parent.flags), Some(3));
+        assert!(TraceParent::parse(&format!("00-{}-{}-01-extra", TRACE_ID, PARENT_ID)).is_none());
+        assert!(TraceParent::parse(&format!("ff-{}-{}-01", TRACE_ID, PARENT_ID)).is_none());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let invalid = [
+            String::new(),
+            format!("00-{}-{}", TRACE_ID, PARENT_ID),
+            format!("0-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-1", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, &PARENT_ID[1..]),
+            format!("00-{}-{}-01", TRACE_ID.to_uppercase(), PARENT_ID),
+            format!("00-{}-{}-0g", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", "0".repeat(32), PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, "0".repeat(16)),
+        ];
+        for value in &invalid {
+            assert!(TraceParent::parse(value).is_none(), "{:?} parsed", value);
+        }
+    }
+
+    /// The message and request ID field of an event or span.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        request_id: Option<u64>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl Visit for Fields {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            if field.name() == "request_id" {
+                self.request_id = Some(value);
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            if field.name() == "message" {
+                self.message = format!("{:?}", value);
+            }
+        }
+    }
+
+    /// The message of each event, with the request ID of the span it was in, if any.
+    #[cfg(feature = "tracing")]
+    type Events = Arc<Mutex<Vec<(String, Option<u64>)>>>;
+
+    /// A subscriber which keeps the events it's given, along with the request span each was in.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Recorder {
+        /// The request IDs of the spans, by span ID, starting from 1.
+        spans: Mutex<Vec<Option<u64>>>,
+        entered: Mutex<Vec<u64>>,
+        events: Events,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attributes: &Attributes) -> Id {
+            let mut fields = Fields::default();
+            attributes.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields.request_id);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let request_id = self.entered.lock().unwrap().last()
+                .and_then(|&span| self.spans.lock().unwrap()[span as usize - 1]);
+            self.events.lock().unwrap().push((fields.message, request_id));
+        }
+
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn respond(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        debug!("handling request");
+        Box::new(request.response().send_headers().and_then(|body| body.finish()))
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn request_spans() {
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let mut client = serve(move |handle, io| {
+            // Record everything on the server's thread from here on.
+            mem::forget(tracing::subscriber::set_default(recorder));
+            let service = FastcgiService::new(handle.remote().clone(), Arc::new(respond));
+            FastcgiProto::new().bind_service(handle, io, service);
+        });
+        let mut requests = request(1);
+        requests.extend(request(2));
+        client.write_all(&requests).unwrap();
+        read_until_ended(&mut client, 2);
+
+        let events = events.lock().unwrap();
+        let mut records = 0;
+        for &(ref message, request_id) in events.iter() {
+            let record = message.starts_with("received ") || message.starts_with("sending ");
+            if let (true, Some(id)) = (record, message.split(" record for request ").nth(1)) {
+                let id = id.parse::<u64>().unwrap();
+                assert_eq!(request_id, Some(id), "{:?} in the wrong span", message);
+                records += 1;
+            }
+        }
+        // BeginRequest and Params in (the empty Stdin just ends the body), and Stdout, its end,
+        // the end of Stderr and EndRequest out, for each request.
+        assert_eq!(records, 12, "{:?}", events);
+        let handled = events.iter()
+            .filter(|(message, _)| message == "handling request")
+            .map(|&(_, request_id)| request_id)
+            .collect::<Vec<_>>();
+        assert_eq!(handled, vec![Some(1), Some(2)]);
+    }
+}
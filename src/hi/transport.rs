@@ -1,20 +1,38 @@
 use super::super::*;
+use super::trace::request_span;
 
-use futures::{Stream, Sink, Poll, StartSend, Async};
+use futures::{task, AsyncSink, Future, Stream, Sink, Poll, StartSend, Async};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_codec::{Decoder, Framed};
 use tokio_proto::streaming::multiplex::*;
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, VecDeque};
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Manages a FastCGI connection, by binding a codec that translates bytes into multiplexed FastCGI
 /// streams. This also closes the connection when no streams are active (unless one of them
-/// specifies the `FCGI_KEEP_CONN` bit in the `BeginRequest` record).
+/// specifies the `FCGI_KEEP_CONN` bit in the `BeginRequest` record, and the server isn't shutting
+/// down). Kept connections can also be closed after being idle for a while, or after serving a
+/// number of requests, with any more requests on them answered with `FCGI_OVERLOADED`.
 pub struct FastcgiTransport<IO: AsyncRead + AsyncWrite + 'static> {
     inner: Option<Framed<IO, FastcgiMultiplexedPipelinedCodec>>,
     in_flight: Requests,
     keep_connection: bool,
+    idle_timeout: Option<(Duration, Handle)>,
+    /// Runs while there are no requests in flight, if there's an idle timeout.
+    idle: Option<Timeout>,
+    max_requests: Option<usize>,
+    /// Requests begun after `max_requests`, whose records are ignored.
+    rejected: BTreeSet<u16>,
+    /// Rejected requests still to be sent their `FCGI_OVERLOADED` end records.
+    overloaded: VecDeque<u16>,
+    metrics: Option<Arc<Metrics>>,
+    span: Span,
+    request_spans: RequestSpans,
+    shutdown: Option<Shutdown>,
 }
 
 // We want to drop connections only if no requests are in flight and we've seen at least one
@@ -24,6 +42,8 @@ pub struct FastcgiTransport<IO: AsyncRead + AsyncWrite + 'static> {
 struct Requests {
     in_flight: BTreeSet<u16>,
     any_yet: bool,
+    /// The number of requests begun on the connection.
+    begun: usize,
 }
 
 impl Requests {
@@ -31,6 +51,7 @@ impl Requests {
         Requests {
             in_flight: BTreeSet::new(),
             any_yet: false,
+            begun: 0,
         }
     }
 
@@ -46,6 +67,50 @@ impl Requests {
     fn is_empty(&self) -> bool {
         self.any_yet && self.in_flight.is_empty()
     }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+}
+
+/// The record in a frame, if it has one.
+fn frame_record(frame: &Frame<FastcgiRecord, FastcgiRecord, io::Error>) -> Option<&FastcgiRecord> {
+    match *frame {
+        Frame::Message { ref message, .. } | Frame::Body { chunk: Some(ref message), .. } => {
+            Some(message)
+        },
+        _ => None,
+    }
+}
+
+/// The name of the type of a record, for events.
+fn record_type(body: &FastcgiRecordBody) -> &'static str {
+    match *body {
+        FastcgiRecordBody::BeginRequest(_) => "BeginRequest",
+        FastcgiRecordBody::AbortRequest => "AbortRequest",
+        FastcgiRecordBody::EndRequest(_) => "EndRequest",
+        FastcgiRecordBody::Params(_) => "Params",
+        FastcgiRecordBody::Stdin(_) => "Stdin",
+        FastcgiRecordBody::Stdout(_) => "Stdout",
+        FastcgiRecordBody::Stderr(_) => "Stderr",
+        FastcgiRecordBody::Data(_) => "Data",
+        FastcgiRecordBody::GetValues(_) => "GetValues",
+        FastcgiRecordBody::GetValuesResult(_) => "GetValuesResult",
+        FastcgiRecordBody::UnknownTypeResponse(_) => "UnknownTypeResponse",
+        FastcgiRecordBody::UnknownType(..) => "UnknownType",
+    }
+}
+
+/// A metrics label for the kind of an error, like `invalid_data`.
+fn error_kind_label(e: &io::Error) -> String {
+    let mut label = String::new();
+    for c in format!("{:?}", e.kind()).chars() {
+        if c.is_uppercase() && !label.is_empty() {
+            label.push('_');
+        }
+        label.push(c.to_ascii_lowercase());
+    }
+    label
 }
 
 impl<IO: AsyncRead + AsyncWrite + 'static> FastcgiTransport<IO> {
@@ -55,10 +120,178 @@ impl<IO: AsyncRead + AsyncWrite + 'static> FastcgiTransport<IO> {
             inner: Some(codec.framed(io)),
             in_flight: Requests::new(),
             keep_connection: false,
+            idle_timeout: None,
+            idle: None,
+            max_requests: None,
+            rejected: BTreeSet::new(),
+            overloaded: VecDeque::new(),
+            metrics: None,
+            span: Span::none(),
+            request_spans: RequestSpans::default(),
+            shutdown: None,
+        }
+    }
+
+    /// Close the connection once it has no requests in flight after the server starts shutting
+    /// down, even if the web server asked to keep it open.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> FastcgiTransport<IO> {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    fn draining(&mut self) -> bool {
+        self.shutdown.as_mut().map(|shutdown| shutdown.poll_draining()).unwrap_or(false)
+    }
+
+    /// Close the connection once it has had no requests in flight for this long, even if the web
+    /// server asked to keep it open.
+    pub fn with_idle_timeout(mut self, timeout: Duration, handle: Handle)
+        -> FastcgiTransport<IO>
+    {
+        self.idle_timeout = Some((timeout, handle));
+        self
+    }
+
+    /// Close the connection once it has no requests in flight after this many requests have begun
+    /// on it, even if the web server asked to keep it open. Requests begun after that are answered
+    /// with `FCGI_OVERLOADED`, so that the web server sends them elsewhere.
+    pub fn with_max_requests_per_connection(mut self, max_requests: usize)
+        -> FastcgiTransport<IO>
+    {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    fn spent(&self) -> bool {
+        self.max_requests.map(|max| self.in_flight.begun >= max).unwrap_or(false)
+    }
+
+    /// Reject the requests begun once the connection is spent, and ignore the rest of their
+    /// records. Returns whether the frame was for a rejected request.
+    fn reject(&mut self, frame: &Frame<FastcgiRecord, FastcgiRecord, io::Error>) -> bool {
+        match *frame {
+            Frame::Message { id, .. } if self.spent() => {
+                debug!("request {} is over this connection's limit; rejecting it", id);
+                self.rejected.insert(id as u16);
+                self.overloaded.push_back(id as u16);
+                true
+            },
+            Frame::Body { id, ref chunk } if self.rejected.contains(&(id as u16)) => {
+                if chunk.is_none() {
+                    self.rejected.remove(&(id as u16));
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Send the rejected requests their `FCGI_OVERLOADED` end records.
+    fn send_overloaded(&mut self) -> Poll<(), io::Error> {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Ok(Async::Ready(())),
+        };
+        while let Some(&id) = self.overloaded.front() {
+            let record = FastcgiRecord {
+                request_id: id,
+                body: FastcgiRecordBody::EndRequest(EndRequest {
+                    app_status: 0,
+                    protocol_status: ProtocolStatus::Overloaded,
+                }),
+            };
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_out(&record);
+            }
+            let frame = Frame::Body { id: RequestId::from(id), chunk: Some(record) };
+            if let AsyncSink::NotReady(_) = inner.start_send(frame)? {
+                return Ok(Async::NotReady);
+            }
+            debug!("sent FCGI_OVERLOADED for request {}", id);
+            self.overloaded.pop_front();
+        }
+        inner.poll_complete()
+    }
+
+    /// Whether the connection should be closed now that it has no requests in flight.
+    fn done(&mut self) -> bool {
+        self.draining() || self.spent()
+    }
+
+    /// Whether the connection has had no requests in flight for longer than the idle timeout.
+    /// This starts the timer when the last request finishes, or before the first one begins.
+    fn idle_expired(&mut self) -> bool {
+        let (duration, handle) = match self.idle_timeout {
+            Some((duration, ref handle)) if self.in_flight.is_idle() => {
+                (duration, handle)
+            },
+            _ => {
+                self.idle = None;
+                return false;
+            },
+        };
+        if self.idle.is_none() {
+            match Timeout::new(duration, handle) {
+                Ok(timeout) => self.idle = Some(timeout),
+                Err(e) => {
+                    warn!("failed to start the idle timeout: {}", e);
+                    return false;
+                },
+            }
+        }
+        match self.idle.as_mut().map(|idle| idle.poll()) {
+            Some(Ok(Async::Ready(()))) => {
+                debug!("connection was idle for {:?}; closing it", duration);
+                true
+            },
+            Some(Err(e)) => {
+                warn!("idle timeout failed: {}", e);
+                self.idle = None;
+                false
+            },
+            _ => false,
+        }
+    }
+
+    /// Record the events on this connection in the given span, made with `connection_span`.
+    pub fn with_span(mut self, span: Span) -> FastcgiTransport<IO> {
+        self.span = span;
+        self
+    }
+
+    /// Keep the spans of the requests on this connection here, for the service to run them in.
+    /// Each request's span is made inside the connection's when the request begins, and the
+    /// events for its records are recorded in it.
+    pub fn with_request_spans(mut self, request_spans: RequestSpans) -> FastcgiTransport<IO> {
+        self.request_spans = request_spans;
+        self
+    }
+
+    /// Count this connection and the records on it in the given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> FastcgiTransport<IO> {
+        metrics.connection_opened();
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Drop the connection, counting it as closed if it was still open.
+    fn close(&mut self) {
+        if self.inner.take().is_some() {
+            let _entered = self.span.enter();
+            debug!("connection closed");
+            if let Some(ref metrics) = self.metrics {
+                metrics.connection_closed();
+            }
         }
     }
 }
 
+impl<IO: AsyncRead + AsyncWrite + 'static> Drop for FastcgiTransport<IO> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
 impl<IO: AsyncRead + AsyncWrite + 'static, ReadBody> Transport<ReadBody> for FastcgiTransport<IO> {
     // This is a nice place to put some extra logic if needed. See:
     // https://tokio-rs.github.io/tokio-proto/tokio_proto/streaming/multiplex/trait.Transport.html
@@ -70,12 +303,27 @@ impl<IO: AsyncRead + AsyncWrite + 'static> Stream for FastcgiTransport<IO> {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         trace!("poll");
+        let span = self.span.clone();
+        let _entered = span.enter();
         match self.inner.as_mut() {
             Some(io) => {
                 debug!("poll: calling inner IO");
                 let result = io.poll();
                 debug!("poll: got {:?}", result);
 
+                if let Ok(Async::Ready(Some(ref frame))) = result {
+                    if self.reject(frame) {
+                        if let (Some(metrics), Some(record)) = (self.metrics.as_ref(),
+                                                                frame_record(frame)) {
+                            metrics.record_in(record);
+                        }
+                        self.send_overloaded()?;
+                        // Look for the next frame, as there's nothing for the service here.
+                        task::current().notify();
+                        return Ok(Async::NotReady);
+                    }
+                }
+
                 let id = match result {
                     Ok(Async::Ready(Some(ref frame))) => {
                         match *frame {
@@ -85,6 +333,13 @@ impl<IO: AsyncRead + AsyncWrite + 'static> Stream for FastcgiTransport<IO> {
                                         debug!("request has FCGI_KEEP_CONN set");
                                         self.keep_connection = true;
                                     }
+                                    self.in_flight.begun += 1;
+                                    let span = request_span(
+                                        Some(&self.span), id as u16, begin_request.role);
+                                    self.request_spans.insert(id as u16, span);
+                                    if self.spent() {
+                                        debug!("request {} is the last on this connection", id);
+                                    }
                                 }
                                 Some(id)
                             }
@@ -98,6 +353,41 @@ impl<IO: AsyncRead + AsyncWrite + 'static> Stream for FastcgiTransport<IO> {
                 if let Some(id) = id {
                     debug!("poll: request ID is {}", id);
                     self.in_flight.request(id);
+                    self.idle = None;
+                }
+
+                if let Ok(Async::Ready(Some(ref frame))) = result {
+                    if let Some(record) = frame_record(frame) {
+                        let span = self.request_spans.get(record.request_id);
+                        let _entered = span.as_ref().map(Span::enter);
+                        debug!("received {} record for request {}", record_type(&record.body),
+                               record.request_id);
+                    }
+                }
+
+                if let Some(ref metrics) = self.metrics {
+                    match result {
+                        Ok(Async::Ready(Some(ref frame))) => {
+                            if let Some(record) = frame_record(frame) {
+                                metrics.record_in(record);
+                            }
+                        },
+                        Err(ref e) => metrics.protocol_error(&error_kind_label(e)),
+                        _ => (),
+                    }
+                }
+
+                if let Ok(Async::NotReady) = result {
+                    // Connections which haven't sent a request yet are closed too.
+                    if self.in_flight.is_idle() && self.done() {
+                        debug!("poll: done with no requests in flight; closing connection");
+                        self.close();
+                        return Ok(Async::Ready(None));
+                    }
+                    if self.idle_expired() {
+                        self.close();
+                        return Ok(Async::Ready(None));
+                    }
                 }
 
                 result
@@ -116,9 +406,20 @@ impl<IO: AsyncRead + AsyncWrite + 'static> Sink for FastcgiTransport<IO> {
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         trace!("start_send: {:?}", item);
+        let _entered = self.span.enter();
+        if let Some(record) = frame_record(&item) {
+            let span = self.request_spans.get(record.request_id);
+            let _entered = span.as_ref().map(Span::enter);
+            debug!("sending {} record for request {}", record_type(&record.body),
+                   record.request_id);
+        }
         if let Frame::Body { id, chunk: None } = item {
             debug!("start_send: request {} is finished", id);
             self.in_flight.remove(id);
+            self.request_spans.remove(id as u16);
+        }
+        if let (Some(metrics), Some(record)) = (self.metrics.as_ref(), frame_record(&item)) {
+            metrics.record_out(record);
         }
 
         self.inner.as_mut().expect("start_send called on dead connection").start_send(item)
@@ -126,6 +427,11 @@ impl<IO: AsyncRead + AsyncWrite + 'static> Sink for FastcgiTransport<IO> {
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         trace!("poll_complete");
+        let span = self.span.clone();
+        let _entered = span.enter();
+        if !self.overloaded.is_empty() {
+            self.send_overloaded()?;
+        }
         let result = match self.inner.as_mut() {
             Some(inner) => inner.poll_complete(),
             None => {
@@ -135,10 +441,163 @@ impl<IO: AsyncRead + AsyncWrite + 'static> Sink for FastcgiTransport<IO> {
                 Ok(Async::Ready(()))
             }
         };
-        if !self.keep_connection && self.in_flight.is_empty() {
+        if self.in_flight.is_empty() && (!self.keep_connection || self.done()) {
             debug!("poll_complete: zero in-flight requests; dropping connection.");
-            self.inner = None;
+            self.close();
+        } else if self.inner.is_some() && self.idle_expired() {
+            self.close();
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    use futures::sync::oneshot;
+
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream as StdUnixStream;
+    use std::thread;
+    use std::time::Instant;
+
+    fn respond(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+        let mut response = request.response();
+        response.set_header("Content-Type", "text/plain");
+        Box::new(response.send_headers().and_then(|body| body.finish()))
+    }
+
+    /// Serve a connection with the proto, and a service which answers every request at once.
+    fn serve_proto<F>(proto: F) -> StdUnixStream
+        where F: FnOnce(&Handle) -> FastcgiProto + Send + 'static
+    {
+        serve(move |handle, io| {
+            let service = FastcgiService::new(handle.remote().clone(), Arc::new(respond));
+            proto(handle).bind_service(handle, io, service);
+        })
+    }
+
+    #[test]
+    fn kept_connection_stays_open() {
+        let mut client = serve_proto(|_| FastcgiProto::new());
+        client.write_all(&request(1)).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut out = vec![0; 4096];
+        let mut read = 0;
+        while end_records(&out[.. read]).is_empty() {
+            read += client.read(&mut out[read ..]).unwrap();
+        }
+        assert_eq!(end_records(&out[.. read]), vec![(1, 0)]);
+        assert!(client.read(&mut out).is_err(), "the connection was closed");
+    }
+
+    #[test]
+    fn max_requests_per_connection() {
+        let mut client = serve_proto(|_| FastcgiProto::new().with_max_requests_per_connection(2));
+        let mut requests = request(1);
+        requests.extend(request(2));
+        requests.extend(request(3));
+        requests.extend(request(4));
+        client.write_all(&requests).unwrap();
+        let mut ends = end_records(&read_until_closed(&mut client));
+        ends.sort();
+        assert_eq!(ends, vec![(1, 0), (2, 0), (3, 2), (4, 2)]);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let timeout = Duration::from_millis(200);
+        let mut client = serve_proto(move |handle| {
+            FastcgiProto::new().with_idle_timeout(timeout, handle.clone())
+        });
+        client.write_all(&request(1)).unwrap();
+        let started = Instant::now();
+        let out = read_until_closed(&mut client);
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn idle_timeout_before_any_request() {
+        let timeout = Duration::from_millis(200);
+        let mut client = serve_proto(move |handle| {
+            FastcgiProto::new().with_idle_timeout(timeout, handle.clone())
+        });
+        let started = Instant::now();
+        assert!(read_until_closed(&mut client).is_empty());
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn idle_timeout_resets_between_requests() {
+        // Each gap is well inside the timeout, but all of them together are well over it.
+        let timeout = Duration::from_secs(1);
+        let mut client = serve_proto(move |handle| {
+            FastcgiProto::new().with_idle_timeout(timeout, handle.clone())
+        });
+        let started = Instant::now();
+        for id in 1 .. 7 {
+            thread::sleep(Duration::from_millis(250));
+            client.write_all(&request(id)).unwrap();
+        }
+        let out = read_until_closed(&mut client);
+        assert_eq!(end_records(&out), vec![(1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0)]);
+        assert!(started.elapsed() >= Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn draining_closes_idle_connection() {
+        let (mut trigger, shutdown) = Shutdown::channel();
+        let mut client = serve_proto(|_| FastcgiProto::new().with_shutdown(shutdown));
+        client.write_all(&request(1)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        trigger.drain();
+        let out = read_until_closed(&mut client);
+        assert_eq!(end_records(&out), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn draining_closes_connection_without_requests() {
+        let (mut trigger, shutdown) = Shutdown::channel();
+        let mut client = serve_proto(|_| FastcgiProto::new().with_shutdown(shutdown));
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        trigger.drain();
+        assert!(read_until_closed(&mut client).is_empty());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn draining_finishes_requests_in_flight() {
+        // Responds a while after it's called, once draining has started.
+        fn respond_later(request: FastcgiRequest) -> Box<dyn Future<Item=(), Error=io::Error>> {
+            let (sender, receiver) = oneshot::channel();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                let _ = sender.send(());
+            });
+            Box::new(receiver.then(move |_| respond(request)))
+        }
+
+        let (mut trigger, shutdown) = Shutdown::channel();
+        let mut client = serve(move |handle, io| {
+            let service = FastcgiService::new(handle.remote().clone(), Arc::new(respond_later))
+                .with_shutdown(shutdown.clone());
+            FastcgiProto::new().with_shutdown(shutdown).bind_service(handle, io, service);
+        });
+        let mut requests = request(1);
+        requests.extend(request(2));
+        client.write_all(&requests).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        trigger.drain();
+        let out = read_until_closed(&mut client);
+        let mut ends = end_records(&out);
+        ends.sort();
+        assert_eq!(ends, vec![(1, 0), (2, 0)]);
+        for id in 1 .. 3 {
+            assert_eq!(app_status(&out, id), Some(0));
+        }
+    }
+}
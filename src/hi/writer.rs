@@ -0,0 +1,212 @@
+use super::super::*;
+use super::response::{broken_pipe, RequestState};
+
+use bytes::BytesMut;
+use futures::{Async, AsyncSink, Poll, Sink};
+use futures::sync::mpsc;
+use tokio_io::AsyncWrite;
+
+use std::cmp;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+const MAX_RECORD_LEN: usize = 0xFFFF;
+
+/// Writes the body of a response as `Stdout` records, for use with code that wants a `Write` or
+/// `AsyncWrite`. Get one with `FastcgiBodyResponse::into_writer`.
+///
+/// Data is sent along as soon as there's a full record's worth of it, or when flushed. Writing
+/// applies backpressure: when the web server isn't accepting records fast enough, writes fail with
+/// `WouldBlock` and the current task is notified once they can proceed, so this must only be used
+/// from within a task. Shutting the writer down flushes it and finishes the response.
+pub struct FastcgiBodyWriter {
+    sender: Option<mpsc::Sender<FastcgiRecord>>,
+    request_id: u16,
+    state: Arc<RequestState>,
+    buffer: BytesMut,
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "response channel is full")
+}
+
+fn shut_down() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "response body writer is already shut down")
+}
+
+impl FastcgiBodyWriter {
+    pub fn new(
+        request_id: u16,
+        sender: mpsc::Sender<FastcgiRecord>,
+        state: Arc<RequestState>,
+        buffer: Vec<u8>,
+        ) -> FastcgiBodyWriter
+    {
+        FastcgiBodyWriter {
+            sender: Some(sender),
+            request_id,
+            state,
+            buffer: BytesMut::from(buffer),
+        }
+    }
+
+    /// Send the buffered data as records: only full records, unless `all` is set.
+    fn poll_send(&mut self, all: bool) -> Poll<(), io::Error> {
+        while self.buffer.len() >= MAX_RECORD_LEN || (all && !self.buffer.is_empty()) {
+            let sender = match self.sender.as_mut() {
+                Some(sender) => sender,
+                None => return Err(shut_down()),
+            };
+
+            if let Async::NotReady = sender.poll_ready().map_err(broken_pipe)? {
+                return Ok(Async::NotReady);
+            }
+
+            let len = cmp::min(self.buffer.len(), MAX_RECORD_LEN);
+            debug!("writer sending {} bytes of body", len);
+            let data = self.buffer.split_to(len);
+            self.state.on_body(&data);
+            let record = FastcgiRecord {
+                request_id: self.request_id,
+                body: FastcgiRecordBody::Stdout(data),
+            };
+            match sender.start_send(record).map_err(broken_pipe)? {
+                AsyncSink::Ready => (),
+                AsyncSink::NotReady(_) => unreachable!("sender was ready"),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Write for FastcgiBodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.sender.is_none() {
+            return Err(shut_down());
+        }
+        if let Async::NotReady = self.poll_send(false)? {
+            return Err(would_block());
+        }
+        let len = cmp::min(buf.len(), MAX_RECORD_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Async::NotReady = self.poll_send(true)? {
+            return Err(would_block());
+        }
+        if let Some(ref mut sender) = self.sender {
+            if let Async::NotReady = sender.poll_complete().map_err(broken_pipe)? {
+                return Err(would_block());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncWrite for FastcgiBodyWriter {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if self.sender.is_none() {
+            return Ok(Async::Ready(()));
+        }
+        match self.flush() {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+        debug!("writer finishing body");
+        self.sender = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for FastcgiBodyWriter {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            warn!("FastcgiBodyWriter dropped with un-flushed buffer of {} bytes!",
+                  self.buffer.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{future, Future, Stream};
+
+    fn writer(sender: mpsc::Sender<FastcgiRecord>) -> FastcgiBodyWriter {
+        FastcgiBodyWriter::new(1, sender, Arc::new(RequestState::default()), vec![])
+    }
+
+    /// Run the function in a task, as the writer has to be used.
+    fn in_task<F: FnOnce() -> R, R>(f: F) -> R {
+        future::lazy(|| Ok::<_, ()>(f())).wait().unwrap()
+    }
+
+    fn stdout(receiver: mpsc::Receiver<FastcgiRecord>) -> Vec<usize> {
+        receiver.wait()
+            .map(|record| match record.unwrap().body {
+                FastcgiRecordBody::Stdout(data) => data.len(),
+                body => panic!("unexpected record {:?}", body),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records() {
+        let (sender, receiver) = mpsc::channel(8);
+        let mut writer = writer(sender);
+        in_task(|| {
+            writer.write_all(&[b'x'; MAX_RECORD_LEN + 10]).unwrap();
+            writer.write_all(b"more").unwrap();
+            writer.shutdown().unwrap();
+        });
+        drop(writer);
+        assert_eq!(stdout(receiver), vec![MAX_RECORD_LEN, 14]);
+    }
+
+    #[test]
+    fn write_after_shutdown() {
+        let (sender, receiver) = mpsc::channel(8);
+        let mut writer = writer(sender);
+        in_task(|| {
+            writer.write_all(b"body").unwrap();
+            assert!(writer.shutdown().unwrap().is_ready());
+            assert_eq!(writer.write(b"more").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+            assert!(writer.shutdown().unwrap().is_ready());
+        });
+        drop(writer);
+        assert_eq!(stdout(receiver), vec![4]);
+    }
+
+    #[test]
+    fn full_channel() {
+        // The channel has room for one record.
+        let (sender, mut receiver) = mpsc::channel(0);
+        let mut writer = writer(sender);
+        in_task(|| {
+            assert_eq!(writer.write(&[b'x'; MAX_RECORD_LEN]).unwrap(), MAX_RECORD_LEN);
+            assert_eq!(writer.write(b"more").unwrap(), 4);
+            assert_eq!(writer.write(&[b'x'; MAX_RECORD_LEN]).unwrap(), MAX_RECORD_LEN - 4);
+            assert_eq!(writer.write(b"more").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+            assert_eq!(writer.flush().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+            assert!(writer.shutdown().unwrap().is_not_ready());
+
+            // Taking a record makes room for the next.
+            let record = receiver.poll().unwrap();
+            assert!(record.is_ready());
+            assert_eq!(writer.write(b"more").unwrap(), 4);
+        });
+        in_task(|| {
+            // Flushing waits until the last record is taken too.
+            assert!(receiver.poll().unwrap().is_ready());
+            assert!(writer.shutdown().unwrap().is_not_ready());
+            assert!(receiver.poll().unwrap().is_ready());
+            assert!(writer.shutdown().unwrap().is_ready());
+        });
+        drop(writer);
+        assert_eq!(stdout(receiver), Vec::<usize>::new());
+    }
+}
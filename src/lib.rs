@@ -1,13 +1,20 @@
+#[cfg(feature = "compression")] extern crate brotli;
 extern crate byteorder;
 extern crate bytes;
 #[macro_use] extern crate enum_primitive;
+#[cfg(feature = "compression")] extern crate flate2;
 extern crate futures;
-#[macro_use] extern crate log;
+extern crate futures_cpupool;
+extern crate libc;
+#[cfg_attr(not(feature = "tracing"), macro_use)] extern crate log;
 extern crate tokio_codec;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_proto;
 extern crate tokio_service;
+#[cfg(feature = "signals")] extern crate tokio_signal;
+extern crate tokio_uds;
+#[cfg(feature = "tracing")] #[macro_use] extern crate tracing;
 
 mod endian;
 mod hi;
@@ -15,12 +22,34 @@ mod lowlevel;
 mod rawstruct;
 mod s11n;
 
+pub use hi::access_log::{AccessLog, AccessLogEntry, LogFormat};
+pub use hi::allow_list::{Cidr, PeerAllowList};
+pub use hi::blocking::{BlockingHandler, BlockingPool};
 pub use hi::codec::FastcgiMultiplexedPipelinedCodec;
+#[cfg(feature = "compression")]
+pub use hi::compress::{CompressedResponse, CompressionConfig, ContentEncoding};
+pub use hi::error_page::ErrorPage;
 pub use hi::handler::FastcgiRequestHandler;
+pub use hi::handler_pool::HandlerPool;
+pub use hi::listener::is_listening_socket;
+pub use hi::metrics::{Metrics, MetricsEndpoint};
+pub use hi::middleware::{Layered, Middleware, MiddlewareStack, ResponseHook};
 pub use hi::proto::FastcgiProto;
-pub use hi::response::{FastcgiRequest, FastcgiHeadersResponse, FastcgiBodyResponse};
+pub use hi::reader::FastcgiBodyReader;
+pub use hi::response::{FastcgiRequest, FastcgiHeadersResponse, FastcgiBodyResponse, WritePolicy};
+pub use hi::router::Router;
+pub use hi::sendfile::{SendfileConfig, SendfileStyle};
+pub use hi::server::{BindAddress, FastcgiServer, FastcgiServerBuilder};
 pub use hi::service::FastcgiService;
+#[cfg(feature = "signals")]
+pub use hi::shutdown::termination_signal;
+pub use hi::shutdown::{RequestLimit, Shutdown, ShutdownError, ShutdownTrigger};
+pub use hi::status::reason_phrase;
 pub use hi::stream_process::StreamProcess;
+pub use hi::systemd::{is_socket_activated, listen_fds, sd_notify, ListenFd};
+pub use hi::timeout::Timeouts;
+pub use hi::trace::{connection_span, RequestSpans, Span, TraceParent};
 pub use hi::transport::FastcgiTransport;
+pub use hi::writer::FastcgiBodyWriter;
 pub use lowlevel::{FastcgiLowlevelCodec, FastcgiRecord, FastcgiRecordBody, BeginRequest, EndRequest};
-pub use s11n::{FASTCGI_VERSION, Role, ProtocolStatus};
+pub use s11n::{FASTCGI_VERSION, FCGI_LISTENSOCK_FILENO, Role, ProtocolStatus};
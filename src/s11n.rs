@@ -4,6 +4,10 @@ use super::endian::*;
 
 pub const FASTCGI_VERSION: u8 = 1;
 
+/// The file descriptor a FastCGI application finds its listening socket on, when it's started by a
+/// web server or process manager.
+pub const FCGI_LISTENSOCK_FILENO: i32 = 0;
+
 // Variables for the RecordType::GetValues and GetValuesResult records.
 //pub const FCGI_MAX_CONNS: &'static str = "FCGI_MAX_CONNS";
 //pub const FCGI_MAX_REQS: &'static str = "FCGI_MAX_REQS";